@@ -1,74 +1,308 @@
-use clap::Parser;
-use std::collections::HashMap;
-use std::error::Error;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
+use indexmap::IndexMap;
+use serde_json::{json, Map, Value};
 use std::path::PathBuf;
 
+use super::build_error::*;
+use super::build_log::*;
 use super::command_args::*;
+use super::file_api::*;
 use super::json_helper::*;
 use super::utility_helper::*;
 
-const NINA_GENERATOR: &str = "Ninja";
+const DEFAULT_GENERATOR: &str = "Ninja";
 const BUILD_LOG_NAME: &str = "build_log.txt";
 
 // AppContext
+#[derive(Clone)]
 pub struct AppContext {
     pub project_location: PathBuf,
     pub workspace_dir: PathBuf,
     pub build_dir: PathBuf,
     pub install_dir: PathBuf,
+    pub package_dir: PathBuf,
+    pub variants: Vec<Variant>,
+    // the variant the pipeline is currently running for, see `for_variant`
     pub variant: Variant,
-    pub configure_args: HashMap<String, String>,
-    pub extra_args: HashMap<String, String>,
+    pub parallel_variants: bool,
+    // number of parallel jobs given to the build tool and ctest
+    pub jobs: usize,
+    pub configure_args: IndexMap<String, String>,
+    // the cache entries removed when configuring (`--unset`)
+    pub unset_options: Vec<String>,
+    pub extra_args: IndexMap<String, String>,
     pub generator: String,
+    // the targets the Build stage builds, all of them when empty
+    pub targets: Vec<String>,
+    pub auto_clean: bool,
     pub stages: Box<[Stage]>,
     pub redirect_outstream: Option<bool>,
-    pub build_log_location: PathBuf,
+    pub log_options: LogOptions,
+    pub test_label: Option<String>,
+    pub test_regex: Option<String>,
+    pub package_generators: Vec<String>,
+    pub install_components: Vec<String>,
+    pub install_prefix: Option<PathBuf>,
+    pub install_strip: bool,
+    pub arguments: ArgumentLists,
+    pub settings_sources: IndexMap<String, (SettingsLayer, Value)>,
+    // the project_settings.json the cache entries are synced into
+    pub settings_file: PathBuf,
+    pub sync_cache: bool,
+    pub show_settings: bool,
     pub project_name: String,
+    pub command: Option<Command>,
+    // progress messages go to stderr when stdout carries the output of a subcommand
+    pub status_to_stderr: bool,
 }
 
 impl AppContext {
-    pub fn new() -> Result<AppContext, Box<dyn Error>> {
+    pub fn new() -> Result<AppContext, BuildError> {
         let args = CommandArgs::parse();
+        // a build needs to be told what to do, the subcommands know it themselves
+        if args.command.is_none() {
+            let missing: Vec<&str> = [
+                (args.stages.is_none(), "--stages <STAGES>"),
+                (args.variant.is_none(), "--variant <VARIANT>"),
+            ]
+            .into_iter()
+            .filter_map(|(missing, arg)| missing.then_some(arg))
+            .collect();
+            if !missing.is_empty() {
+                CommandArgs::command()
+                    .bin_name(env!("CARGO_PKG_NAME"))
+                    .error(
+                        ErrorKind::MissingRequiredArgument,
+                        format!(
+                            "the following required arguments were not provided:\n  {}",
+                            missing.join("\n  ")
+                        ),
+                    )
+                    .exit();
+            }
+        }
 
-        let (configure_args, extra_args, workspace_dir, project_name) =
-            UtilityHelper::current_working_directory(&args.project_location, || {
-                let json_filepath = JsonUtil::create_project_setting(&args.project_location)?;
-                let (configure_args, extra_args, workspace_dir) =
-                    JsonUtil::parse_json(&json_filepath)?;
-                Ok((
-                    configure_args,
-                    extra_args,
-                    workspace_dir,
-                    UtilityHelper::fetch_project_name(),
-                ))
-            })?;
-        let project_name = match &project_name {
+        // every path below is derived from the absolute project location,
+        // the process working directory is never changed
+        let project_location = args.project_location.canonicalize().map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!(
+                    "project location {}: {}",
+                    args.project_location.display(),
+                    e
+                ),
+            )
+        })?;
+
+        let status_to_stderr = matches!(
+            args.command,
+            Some(Command::Targets { json: true } | Command::Run { .. })
+        );
+
+        let json_filepath = JsonUtil::create_project_setting(&project_location, status_to_stderr)?;
+        JsonUtil::migrate_project_setting(&json_filepath, status_to_stderr)?;
+
+        // the command line is the settings layer with the highest precedence
+        let mut command_line = Map::new();
+        if let Some(generator) = &args.generator {
+            command_line.insert("generator".to_string(), json!(generator));
+        }
+        if !args.targets.is_empty() {
+            command_line.insert("default_targets".to_string(), json!(args.targets));
+        }
+        let mut test = Map::new();
+        if let Some(label) = &args.test_label {
+            test.insert("label".to_string(), json!(label));
+        }
+        if let Some(regex) = &args.test_regex {
+            test.insert("regex".to_string(), json!(regex));
+        }
+        if !test.is_empty() {
+            command_line.insert("test".to_string(), Value::Object(test));
+        }
+        let mut project_options = Map::new();
+        for (name, value) in &args.defines {
+            insert_option(&mut project_options, name, value);
+        }
+        // the keys `-D` sets, the last one wins for an option given more than once
+        let defined: Vec<String> = project_options.keys().cloned().collect();
+        if !project_options.is_empty() {
+            command_line.insert(
+                "project_options".to_string(),
+                Value::Object(project_options),
+            );
+        }
+
+        if args.save && (!args.defines.is_empty() || !args.unset.is_empty()) {
+            JsonUtil::save_project_options(
+                &json_filepath,
+                &args.defines,
+                &args.unset,
+                status_to_stderr,
+            )?;
+        }
+
+        let mut settings = JsonUtil::load_settings(&project_location, command_line)?;
+
+        // `-D` replaces the option whichever layer set it and with whichever type
+        for key in &defined {
+            let entry = key.strip_prefix("-D").unwrap_or(key);
+            let entry = entry.split_once(':').map_or(entry, |(entry, _)| entry);
+            let replaced = |other: &str| other != key && is_option_key(other, entry);
+            settings.configure_args.retain(|other, _| !replaced(other));
+            settings.sources.retain(|key_path, _| {
+                key_path
+                    .strip_prefix("project_options.")
+                    .is_none_or(|other| !replaced(other))
+            });
+        }
+        // `--unset` removes the option whichever layer set it
+        for name in &args.unset {
+            settings
+                .configure_args
+                .retain(|key, _| !is_option_key(key, name));
+            settings.sources.retain(|key_path, _| {
+                key_path
+                    .strip_prefix("project_options.")
+                    .is_none_or(|key| !is_option_key(key, name))
+            });
+        }
+        let project_name = match &UtilityHelper::fetch_project_name(&project_location) {
             Some(project_name) => project_name.clone(),
             None => String::from("Not found"),
         };
 
+        let generator = settings
+            .generator
+            .unwrap_or_else(|| DEFAULT_GENERATOR.to_string());
+
+        let mut variants: Vec<Variant> = Vec::new();
+        for name in args.variant.as_deref().unwrap_or("debug").split(',') {
+            let variant = Variant::resolve(name.trim(), &settings.variants)
+                .map_err(|message| BuildError::UnknownVariant { message })?;
+            // a variant given twice would build into the same directories twice, concurrently
+            // with `--parallel-variants`
+            if !variants
+                .iter()
+                .any(|known| known.as_str() == variant.as_str())
+            {
+                variants.push(variant);
+            }
+        }
+
+        // a subcommand works on a single variant
+        if let Some(command) = &args.command {
+            if variants.len() > 1 {
+                let name = match command {
+                    Command::Targets { .. } => "targets",
+                    Command::Run { .. } => "run",
+                };
+                CommandArgs::command()
+                    .bin_name(env!("CARGO_PKG_NAME"))
+                    .error(
+                        ErrorKind::ArgumentConflict,
+                        format!("the `{}` command takes a single variant", name),
+                    )
+                    .exit();
+            }
+        }
+
+        // the variants would overwrite each other's files in a shared prefix
+        let installing = args
+            .stages
+            .as_ref()
+            .is_some_and(|stages| stages.contains(&Stage::Install));
+        if installing && variants.len() > 1 && settings.install_prefix.is_some() {
+            return Err(BuildError::SettingsInvalid {
+                message: String::from(
+                    "`install.prefix` is shared by every variant, install one variant at a time \
+                     or leave it empty to install each into workspace_dir/install/<variant>",
+                ),
+            });
+        }
+
+        // concurrent variants can't share the console, give each its own build log
+        let parallel_variants = args.parallel_variants && variants.len() > 1;
+        // subcommands may configure or build the variant, keep cmake's output out of theirs
+        let (redirect_outstream, tee) = if parallel_variants || args.command.is_some() {
+            (Some(true), false)
+        } else {
+            (args.redirect_outstream, args.tee)
+        };
+
+        // already absolute, see `JsonUtil::load_settings`
+        let workspace_dir = settings.workspace_dir;
         let build_location = workspace_dir.join("build");
         let install_location = workspace_dir.join("install");
-        let build_log_path = build_location
-            .join(args.variant.as_str())
-            .join(BUILD_LOG_NAME);
+        let package_location = workspace_dir.join("package");
 
         Ok(AppContext {
-            project_location: args.project_location,
-            workspace_dir: workspace_dir,
+            project_location,
+            workspace_dir,
             build_dir: build_location,
             install_dir: install_location,
-            variant: args.variant,
-            configure_args: configure_args,
-            extra_args: extra_args,
-            generator: NINA_GENERATOR.to_string(),
-            stages: args.stages,
-            redirect_outstream: args.redirect_outstream,
-            build_log_location: build_log_path,
-            project_name: project_name,
+            package_dir: package_location,
+            variant: variants[0].clone(),
+            variants,
+            parallel_variants,
+            jobs: num_cpus::get(),
+            configure_args: settings.configure_args,
+            unset_options: args.unset,
+            extra_args: settings.extra_args,
+            generator,
+            targets: settings.default_targets,
+            auto_clean: args.auto_clean,
+            stages: args.stages.unwrap_or_default(),
+            redirect_outstream,
+            log_options: LogOptions {
+                tags: args.log_tags,
+                timestamps: args.log_timestamps,
+                tee,
+            },
+            test_label: settings.test_label,
+            test_regex: settings.test_regex,
+            package_generators: settings.package_generators,
+            install_components: settings.install_components,
+            install_prefix: settings.install_prefix,
+            install_strip: settings.install_strip,
+            arguments: settings.arguments,
+            settings_sources: settings.sources,
+            settings_file: json_filepath,
+            sync_cache: args.sync_cache,
+            show_settings: args.show_settings,
+            status_to_stderr,
+            command: args.command,
+            project_name,
         })
     }
 
+    // Returns a copy of the context running the pipeline for `variant` with `jobs` parallel jobs.
+    pub fn for_variant(&self, variant: &Variant, jobs: usize) -> AppContext {
+        AppContext {
+            variant: variant.clone(),
+            jobs,
+            ..self.clone()
+        }
+    }
+
+    // Returns what cmake reported through the file API when it last configured the current
+    // variant, or None if it hasn't been configured by build-it yet.
+    pub fn file_api(&self) -> Result<Option<FileApiReply>, BuildError> {
+        read_reply(&self.build_dir.join(self.variant.as_str()))
+    }
+
+    pub fn build_log_location(&self, variant: &Variant) -> PathBuf {
+        self.build_dir.join(variant.as_str()).join(BUILD_LOG_NAME)
+    }
+
+    // Returns the settings layer the final value of `key_path` came from,
+    // or None if no layer set it.
+    pub fn setting_source(&self, key_path: &str) -> Option<SettingsLayer> {
+        self.settings_sources.get(key_path).map(|(kind, _)| *kind)
+    }
+
     pub fn has_stage(&self, stage_value: Stage) -> bool {
         self.stages.contains(&stage_value)
     }
@@ -0,0 +1,96 @@
+use std::ffi::OsString;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// A process invocation described as a program plus an ordered list of arguments.
+// Arguments are handed to the OS verbatim (no shell is involved), so paths with
+// spaces and values containing quotes, `;` lists or `$` reach the tool untouched.
+#[derive(Clone, Debug)]
+pub struct BuildCommand {
+    pub program: String,
+    pub args: Vec<OsString>,
+    pub envs: Vec<(OsString, OsString)>,
+    pub current_dir: Option<PathBuf>,
+}
+
+impl BuildCommand {
+    pub fn new(program: &str) -> BuildCommand {
+        BuildCommand {
+            program: program.to_string(),
+            args: Vec::new(),
+            envs: Vec::new(),
+            current_dir: None,
+        }
+    }
+
+    pub fn arg<S: Into<OsString>>(&mut self, arg: S) -> &mut BuildCommand {
+        self.args.push(arg.into());
+        self
+    }
+
+    pub fn args<I, S>(&mut self, args: I) -> &mut BuildCommand
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn env<K: Into<OsString>, V: Into<OsString>>(
+        &mut self,
+        key: K,
+        value: V,
+    ) -> &mut BuildCommand {
+        self.envs.push((key.into(), value.into()));
+        self
+    }
+
+    pub fn current_dir(&mut self, dir: &Path) -> &mut BuildCommand {
+        self.current_dir = Some(dir.to_path_buf());
+        self
+    }
+
+    // Creates the std::process::Command ready to be spawned.
+    pub fn to_command(&self) -> Command {
+        let mut command = Command::new(&self.program);
+        command.args(&self.args);
+        command.envs(self.envs.iter().map(|(key, value)| (key, value)));
+        if let Some(dir) = &self.current_dir {
+            command.current_dir(dir);
+        }
+        command
+    }
+}
+
+// Quotes an argument for display so the printed command can be copy-pasted into a shell.
+fn quote_for_display(arg: &str) -> String {
+    let needs_quotes = arg.is_empty()
+        || arg
+            .chars()
+            .any(|c| c.is_whitespace() || "\"'`$;&|<>()*?![]{}#~\\".contains(c));
+    if needs_quotes {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    } else {
+        arg.to_string()
+    }
+}
+
+impl fmt::Display for BuildCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (key, value) in &self.envs {
+            write!(
+                f,
+                "{}={} ",
+                key.to_string_lossy(),
+                quote_for_display(&value.to_string_lossy())
+            )?;
+        }
+        write!(f, "{}", quote_for_display(&self.program))?;
+        for arg in &self.args {
+            write!(f, " {}", quote_for_display(&arg.to_string_lossy()))?;
+        }
+        Ok(())
+    }
+}
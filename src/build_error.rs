@@ -0,0 +1,161 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+// Errors that can stop the build pipeline. They are propagated up to `main`,
+// which reports them and exits with the code returned by `BuildError::exit_code`:
+//
+//   0  success
+//   2  invalid command line (reported by clap)
+//   3  configure stage failed (including a generator mismatch in the build directory)
+//   4  build or install stage failed
+//   5  a required tool (e.g. cmake) could not be found
+//   6  project_settings.json is missing values or malformed, or the workspace doesn't exist
+//   7  I/O error (creating directories, writing logs, ...)
+//   8  test stage ran but some tests failed
+//   9  package stage failed
+//  10  unknown variant or target
+#[derive(Debug)]
+pub enum BuildError {
+    UnknownVariant {
+        message: String,
+    },
+    UnknownTarget {
+        message: String,
+    },
+    ConfigureFailed {
+        code: Option<i32>,
+    },
+    GeneratorMismatch {
+        build_dir: PathBuf,
+        cached: String,
+        requested: String,
+    },
+    BuildFailed {
+        code: Option<i32>,
+    },
+    InstallFailed {
+        code: Option<i32>,
+    },
+    ToolNotFound {
+        program: String,
+    },
+    SettingsInvalid {
+        message: String,
+    },
+    WorkspaceMissing {
+        workspace_dir: PathBuf,
+    },
+    TestsFailed {
+        failed: usize,
+        code: Option<i32>,
+    },
+    PackageFailed {
+        code: Option<i32>,
+    },
+    Io(std::io::Error),
+}
+
+impl BuildError {
+    pub fn exit_code(&self) -> ExitCode {
+        let code = match self {
+            BuildError::ConfigureFailed { .. } | BuildError::GeneratorMismatch { .. } => 3,
+            BuildError::BuildFailed { .. } | BuildError::InstallFailed { .. } => 4,
+            BuildError::ToolNotFound { .. } => 5,
+            BuildError::SettingsInvalid { .. } | BuildError::WorkspaceMissing { .. } => 6,
+            BuildError::Io(_) => 7,
+            BuildError::TestsFailed { .. } => 8,
+            BuildError::PackageFailed { .. } => 9,
+            BuildError::UnknownVariant { .. } | BuildError::UnknownTarget { .. } => 10,
+        };
+        ExitCode::from(code)
+    }
+}
+
+// Formats the exit code of a child process, which is missing if it was killed by a signal.
+fn describe_code(code: &Option<i32>) -> String {
+    match code {
+        Some(code) => format!("exit code {}", code),
+        None => String::from("terminated by signal"),
+    }
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::UnknownVariant { message } | BuildError::UnknownTarget { message } => {
+                write!(f, "{}", message)
+            }
+            BuildError::ConfigureFailed { code } => {
+                write!(f, "configure failed ({})", describe_code(code))
+            }
+            BuildError::GeneratorMismatch {
+                build_dir,
+                cached,
+                requested,
+            } => write!(
+                f,
+                "build directory {} was configured with generator \"{}\" but \"{}\" was requested. \
+                 Run the `clean` stage or pass `--auto-clean` to reconfigure it.",
+                build_dir.display(),
+                cached,
+                requested
+            ),
+            BuildError::BuildFailed { code } => {
+                write!(f, "build failed ({})", describe_code(code))
+            }
+            BuildError::InstallFailed { code } => {
+                write!(f, "install failed ({})", describe_code(code))
+            }
+            BuildError::ToolNotFound { program } => {
+                write!(
+                    f,
+                    "`{}` could not be found, is it installed and on PATH?",
+                    program
+                )
+            }
+            BuildError::SettingsInvalid { message } => {
+                write!(f, "invalid project settings: {}", message)
+            }
+            BuildError::WorkspaceMissing { workspace_dir } => write!(
+                f,
+                "workspace directory {} does not exist, create it or set `workspace_dir` \
+                 in project_settings.json",
+                workspace_dir.display()
+            ),
+            BuildError::TestsFailed { failed, code } => {
+                write!(f, "{} test(s) failed ({})", failed, describe_code(code))
+            }
+            BuildError::PackageFailed { code } => {
+                write!(f, "packaging failed ({})", describe_code(code))
+            }
+            BuildError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BuildError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for BuildError {
+    fn from(e: std::io::Error) -> Self {
+        BuildError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for BuildError {
+    fn from(e: serde_json::Error) -> Self {
+        if e.is_io() {
+            return BuildError::Io(e.into());
+        }
+        BuildError::SettingsInvalid {
+            message: e.to_string(),
+        }
+    }
+}
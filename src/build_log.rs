@@ -0,0 +1,137 @@
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::process::Child;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+
+use super::build_command::*;
+
+// Options controlling how the output of a redirected command is written to the build log.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LogOptions {
+    // prefix every line with the stream it came from, `[out]` or `[err]`
+    pub tags: bool,
+    // prefix every line with the time elapsed since the command started
+    pub timestamps: bool,
+    // also echo every line to the console
+    pub tee: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+impl OutputStream {
+    fn tag(&self) -> &str {
+        match self {
+            OutputStream::Stdout => "[out]",
+            OutputStream::Stderr => "[err]",
+        }
+    }
+}
+
+// Reads `source` line by line on a separate thread and forwards each line to `sender`.
+fn spawn_reader<R: Read + Send + 'static>(
+    source: R,
+    stream: OutputStream,
+    sender: mpsc::Sender<(OutputStream, String)>,
+) -> thread::JoinHandle<std::io::Result<()>> {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(source);
+        let mut buffer = Vec::new();
+        loop {
+            buffer.clear();
+            if reader.read_until(b'\n', &mut buffer)? == 0 {
+                break;
+            }
+            let line = String::from_utf8_lossy(&buffer)
+                .trim_end_matches(['\r', '\n'])
+                .to_string();
+            if sender.send((stream, line)).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    })
+}
+
+// Drains the piped stdout and stderr of `child` concurrently and appends them to the
+// log file at `log_path` in the order the lines arrive, or prints them to the console
+// without a log. Reading both pipes at once keeps a chatty stream from filling its pipe
+// and blocking the child. The stdout lines are also collected into `captured` if given.
+pub fn stream_output(
+    child: &mut Child,
+    cmd: &BuildCommand,
+    log_path: Option<&Path>,
+    options: LogOptions,
+    mut captured: Option<&mut Vec<String>>,
+) -> std::io::Result<()> {
+    let mut logfile = match log_path {
+        Some(log_path) => {
+            let mut logfile = OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(log_path)?;
+            writeln!(logfile)?;
+            writeln!(logfile, "{}", cmd)?;
+            writeln!(logfile)?;
+            Some(logfile)
+        }
+        None => None,
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    let mut readers = Vec::new();
+    if let Some(stdout) = child.stdout.take() {
+        readers.push(spawn_reader(stdout, OutputStream::Stdout, sender.clone()));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        readers.push(spawn_reader(stderr, OutputStream::Stderr, sender.clone()));
+    }
+    // the loop below ends once every reader has dropped its sender
+    drop(sender);
+
+    let start_time = Instant::now();
+    for (stream, line) in receiver {
+        let mut prefix = String::new();
+        if options.timestamps {
+            let elapsed = start_time.elapsed();
+            prefix.push_str(&format!(
+                "[{:>5}.{:03}] ",
+                elapsed.as_secs(),
+                elapsed.subsec_millis()
+            ));
+        }
+        if options.tags {
+            prefix.push_str(stream.tag());
+            prefix.push(' ');
+        }
+
+        if let Some(logfile) = &mut logfile {
+            writeln!(logfile, "{}{}", prefix, line)?;
+        }
+
+        if options.tee || logfile.is_none() {
+            match stream {
+                OutputStream::Stdout => println!("{}", line),
+                OutputStream::Stderr => eprintln!("{}", line),
+            }
+        }
+
+        if let (OutputStream::Stdout, Some(captured)) = (stream, &mut captured) {
+            captured.push(line);
+        }
+    }
+
+    for reader in readers {
+        reader
+            .join()
+            .unwrap_or_else(|_| Err(std::io::Error::other("log reader thread panicked")))?;
+    }
+
+    Ok(())
+}
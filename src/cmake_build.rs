@@ -1,177 +1,1128 @@
-use std::io::{BufRead, BufReader, Write};
-use std::process::{exit, Command, Stdio};
+use std::process::{ExitCode, ExitStatus, Stdio};
 
 use super::app_context::*;
+use super::build_command::*;
+use super::build_error::*;
+use super::build_log::*;
+use super::cmake_cache::*;
 use super::command_args::*;
+use super::file_api::*;
+use super::json_helper::*;
 use super::utility_helper::*;
 
 use colored::*;
+use indexmap::IndexMap;
+use regex::Regex;
+use serde_json::{json, Value};
 
-use num_cpus;
-use std::error::Error;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
 
-// TODO: can't handle spaces in command
-// https://stackoverflow.com/questions/44757893/cmd-c-doesnt-work-in-rust-when-command-includes-spaces
-// This function runs a shell command and waits for it to complete before returning.
-fn run_command(cmd: &str, context: &AppContext) -> Result<(), std::io::Error> {
+const CTEST_LOG_NAME: &str = "ctest_log.txt";
+// where the dynamic loader looks for the shared libraries of a program started by `run`
+const LIBRARY_PATH_VAR: &str = "LD_LIBRARY_PATH";
+
+// Targets the generators provide that don't appear in the code model, the Makefile and
+// Ninja names first, then the ones of the Visual Studio and Xcode generators.
+const BUILTIN_TARGETS: [&str; 18] = [
+    "all",
+    "clean",
+    "help",
+    "install",
+    "install/local",
+    "install/strip",
+    "preinstall",
+    "test",
+    "package",
+    "package_source",
+    "edit_cache",
+    "rebuild_cache",
+    "list_install_components",
+    "ALL_BUILD",
+    "ZERO_CHECK",
+    "INSTALL",
+    "RUN_TESTS",
+    "PACKAGE",
+];
+
+// Generators that accept a `-j N` flag forwarded after `--` to the native build tool.
+const MAKE_STYLE_GENERATORS: [&str; 6] = [
+    "Ninja",
+    "Ninja Multi-Config",
+    "Unix Makefiles",
+    "MinGW Makefiles",
+    "MSYS Makefiles",
+    "Watcom WMake",
+];
+
+// This function spawns the given command and waits for it to complete before returning
+// its exit status. A program that can't be found is reported as `BuildError::ToolNotFound`.
+fn run_command(cmd: &BuildCommand, context: &AppContext) -> Result<ExitStatus, BuildError> {
+    spawn_command(cmd, context, None)
+}
+
+// Like `run_command`, also collecting the lines the command prints on stdout into `output`.
+fn run_command_with_output(
+    cmd: &BuildCommand,
+    context: &AppContext,
+    output: &mut Vec<String>,
+) -> Result<ExitStatus, BuildError> {
+    spawn_command(cmd, context, Some(output))
+}
+
+fn spawn_command(
+    cmd: &BuildCommand,
+    context: &AppContext,
+    captured: Option<&mut Vec<String>>,
+) -> Result<ExitStatus, BuildError> {
     let redirect_outstream = context.redirect_outstream.is_some();
+    // the output has to be read by build-it to be logged or captured
+    let piped = redirect_outstream || captured.is_some();
 
-    let mut command = match std::env::consts::OS {
-        "windows" => Command::new("cmd")
-            .args(&["/C", cmd])
-            .stdout(if redirect_outstream {
-                Stdio::piped()
-            } else {
-                Stdio::inherit()
-            })
-            .stderr(if redirect_outstream {
-                Stdio::piped()
-            } else {
-                Stdio::inherit()
-            })
-            .spawn()?,
-        _ => Command::new("sh")
-            .arg("-c")
-            .arg(cmd)
-            .stdout(if redirect_outstream {
-                Stdio::piped()
-            } else {
-                Stdio::inherit()
-            })
-            .stderr(if redirect_outstream {
-                Stdio::piped()
+    let mut command = cmd
+        .to_command()
+        .stdout(if piped {
+            Stdio::piped()
+        } else {
+            Stdio::inherit()
+        })
+        .stderr(if piped {
+            Stdio::piped()
+        } else {
+            Stdio::inherit()
+        })
+        .spawn()
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => BuildError::ToolNotFound {
+                program: cmd.program.clone(),
+            },
+            _ => BuildError::Io(e),
+        })?;
+
+    if piped {
+        let log_path = context.build_log_location(&context.variant);
+        if let Err(e) = stream_output(
+            &mut command,
+            cmd,
+            redirect_outstream.then_some(log_path.as_path()),
+            context.log_options,
+            captured,
+        ) {
+            // nobody reads the child's output anymore, don't leave it running
+            let _ = command.kill();
+            let _ = command.wait();
+            return Err(e.into());
+        }
+    }
+
+    Ok(command.wait()?)
+}
+
+// Counts of test results reported by ctest.
+#[derive(Debug, Default, PartialEq)]
+struct TestSummary {
+    passed: usize,
+    failed: usize,
+    skipped: usize,
+}
+
+// Tallies the per-test result lines of a ctest output log, e.g.
+// `1/3 Test #1: unit_tests .......   Passed    0.01 sec`
+// `2/3 Test #2: slow_tests .......***Skipped   0.00 sec`
+fn parse_ctest_log(contents: &str) -> TestSummary {
+    let re = Regex::new(r"Test\s+#\d+: .+? \.+(.*)$").unwrap();
+
+    let mut summary = TestSummary::default();
+    for line in contents.lines() {
+        if let Some(captures) = re.captures(line.trim_end()) {
+            let result = &captures[1];
+            if result.contains("Passed") {
+                summary.passed += 1;
+            } else if result.contains("Skipped") || result.contains("Not Run (Disabled)") {
+                summary.skipped += 1;
             } else {
-                Stdio::inherit()
-            })
-            .spawn()?,
+                summary.failed += 1;
+            }
+        }
+    }
+    summary
+}
+
+// Runs ctest in the variant build directory and prints a summary of the results.
+fn run_ctest(build_dir: &Path, context: &AppContext) -> Result<(), BuildError> {
+    let ctest_log_path = build_dir.join(CTEST_LOG_NAME);
+    if ctest_log_path.exists() {
+        fs::remove_file(&ctest_log_path)?;
+    }
+
+    let mut command = BuildCommand::new("ctest");
+    command
+        .current_dir(build_dir)
+        .args(["-C", context.variant.build_type()])
+        .args(["-j", &context.jobs.to_string()])
+        .arg("--output-on-failure")
+        .arg("--output-log")
+        .arg(&ctest_log_path);
+    if let Some(label) = &context.test_label {
+        command.arg("-L").arg(label);
+    }
+    if let Some(regex) = &context.test_regex {
+        command.arg("-R").arg(regex);
+    }
+
+    println!("{}", command.to_string().green());
+
+    let status = run_command(&command, context)?;
+    let summary = parse_ctest_log(&fs::read_to_string(&ctest_log_path).unwrap_or_default());
+
+    let summary_msg = format!(
+        "Test Summary: {} passed, {} failed, {} skipped",
+        summary.passed, summary.failed, summary.skipped
+    );
+    if status.success() {
+        println!("{}", summary_msg.green().bold());
+        Ok(())
+    } else {
+        println!("{}", summary_msg.red().bold());
+        Err(BuildError::TestsFailed {
+            failed: summary.failed,
+            code: status.code(),
+        })
+    }
+}
+
+// Installs the variant with `cmake --install`, once per configured component
+// (or once for everything when no component is set).
+fn run_install(build_dir: &Path, prefix: &Path, context: &AppContext) -> Result<(), BuildError> {
+    let components: Vec<Option<&String>> = if context.install_components.is_empty() {
+        vec![None]
+    } else {
+        context.install_components.iter().map(Some).collect()
     };
 
-    if redirect_outstream {
-        let mut logfile = std::fs::OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(&context.build_log_location)?;
-
-        writeln!(logfile)?;
-        writeln!(logfile, "{}", cmd)?;
-        writeln!(logfile)?;
-
-        let stdout = command.stdout.take().unwrap();
-        let mut reader = BufReader::new(stdout);
-        let mut buffer = String::new();
-        loop {
-            let length = reader.read_line(&mut buffer)?;
-            if length == 0 {
-                break;
-            }
-            logfile.write_all(buffer.as_bytes())?;
-            buffer.clear();
+    for component in components {
+        let mut command = BuildCommand::new("cmake");
+        command
+            .current_dir(build_dir)
+            .arg("--install")
+            .arg(build_dir)
+            .args(["--config", context.variant.build_type()])
+            .arg("--prefix")
+            .arg(prefix);
+        if let Some(component) = component {
+            command.arg("--component").arg(component);
+        }
+        if context.install_strip {
+            command.arg("--strip");
+        }
+        command.args(&context.arguments.install);
+
+        println!("{}", command.to_string().green());
+
+        let status = run_command(&command, context)?;
+        if !status.success() {
+            return Err(BuildError::InstallFailed {
+                code: status.code(),
+            });
         }
     }
 
-    let status = command.wait()?;
+    Ok(())
+}
+
+// Returns the packages cpack reports in its output, e.g.
+// `CPack: - package: /work/package/Debug/app-1.0-Linux.tar.gz generated.`
+fn parse_cpack_packages(output: &[String]) -> Vec<PathBuf> {
+    output
+        .iter()
+        .filter_map(|line| {
+            line.trim()
+                .strip_prefix("CPack: - package: ")?
+                .strip_suffix(" generated.")
+        })
+        .map(PathBuf::from)
+        .collect()
+}
+
+// Runs cpack for the variant and writes the packages to `package_dir`,
+// then prints the path and size of every package produced by this run.
+fn run_cpack(
+    build_dir: &Path,
+    package_dir: &PathBuf,
+    context: &AppContext,
+) -> Result<(), BuildError> {
+    let mut command = BuildCommand::new("cpack");
+    command
+        .current_dir(build_dir)
+        .args(["-C", context.variant.build_type()])
+        .arg("--config")
+        .arg(build_dir.join("CPackConfig.cmake"))
+        .arg("-B")
+        .arg(package_dir);
+    if !context.package_generators.is_empty() {
+        command.arg("-G").arg(context.package_generators.join(";"));
+    }
+
+    println!("{}", command.to_string().green());
+
+    let mut output = Vec::new();
+    let status = run_command_with_output(&command, context, &mut output)?;
     if !status.success() {
-        exit(status.code().unwrap_or(1));
+        return Err(BuildError::PackageFailed {
+            code: status.code(),
+        });
+    }
+
+    // cpack keeps its staging area and packages from earlier runs in the same directory,
+    // the packages of this run are the ones it reports
+    let mut packages = Vec::new();
+    for path in parse_cpack_packages(&output) {
+        let size = fs::metadata(&path)?.len();
+        packages.push((path, size));
+    }
+    packages.sort();
+
+    let colored_package_dirpath = format!("Package Success: {}", package_dir.display())
+        .green()
+        .bold();
+    println!("{}", colored_package_dirpath);
+    for (path, size) in packages {
+        println!(
+            "{}",
+            format!(
+                "\t{} ({})",
+                path.display(),
+                UtilityHelper::format_size(size)
+            )
+            .green()
+        );
     }
 
     Ok(())
 }
 
+// Returns the arguments to forward to the native build tool so that it builds
+// with `jobs` parallel processes, or an empty list if the generator's tool
+// has no such flag (or already parallelizes on its own).
+fn native_parallel_args(generator: &str, jobs: usize) -> Vec<String> {
+    if MAKE_STYLE_GENERATORS.contains(&generator) {
+        vec!["-j".to_string(), jobs.to_string()]
+    } else if generator.starts_with("Visual Studio") {
+        vec![format!("/m:{}", jobs)]
+    } else if generator == "Xcode" {
+        vec!["-jobs".to_string(), jobs.to_string()]
+    } else {
+        vec![]
+    }
+}
+
+// Prints a one line overview of the configured variant from the file API reply.
+fn print_code_model(reply: &FileApiReply, context: &AppContext) {
+    let targets = reply
+        .configuration(context.variant.build_type())
+        .map_or(&[][..], |configuration| &configuration.targets);
+    let executables: Vec<&str> = targets
+        .iter()
+        .filter(|target| target.kind == TargetKind::Executable)
+        .map(|target| target.name.as_str())
+        .collect();
+    let mut msg = format!(
+        "Configured {}: {} targets (executables: {})",
+        context.variant.as_str(),
+        targets.len(),
+        executables.join(", ")
+    );
+    // the other replies are missing with older versions of cmake
+    if let Some(cache) = &reply.cache {
+        msg.push_str(&format!(", {} cache entries", cache.len()));
+    }
+    if let Some(cmake_files) = &reply.cmake_files {
+        let listfiles = cmake_files
+            .iter()
+            .filter(|file| file.is_project_file())
+            .count();
+        msg.push_str(&format!(", {} project listfiles", listfiles));
+    }
+    if let Some(toolchains) = &reply.toolchains {
+        let compilers: Vec<String> = toolchains
+            .iter()
+            .map(|toolchain| {
+                let compiler = &toolchain.compiler;
+                let mut description = format!(
+                    "{} {} {}",
+                    toolchain.language,
+                    compiler.id.as_deref().unwrap_or("unknown"),
+                    compiler.version.as_deref().unwrap_or("")
+                )
+                .trim_end()
+                .to_string();
+                if let Some(path) = &compiler.path {
+                    description.push_str(&format!(" ({})", path.display()));
+                }
+                description
+            })
+            .collect();
+        msg.push_str(&format!("\n\tCompilers: {}", compilers.join(", ")));
+    }
+    UtilityHelper::print_status(msg.blue(), context.status_to_stderr);
+}
+
+// Reports a file API reply that couldn't be read, the steps that use it are skipped.
+fn warn_file_api(context: &AppContext, e: &BuildError) {
+    let msg = format!("Warning: ignoring the CMake file API reply, {}", e);
+    UtilityHelper::print_status(msg.yellow(), context.status_to_stderr);
+}
+
+// Checks the targets to build against the targets of the configured variant and suggests
+// the closest known name for a misspelled one. Nothing is checked if cmake didn't answer
+// the file API query, cmake reports unknown targets itself.
+fn check_targets(context: &AppContext) -> Result<(), BuildError> {
+    let reply = match context.file_api() {
+        Ok(Some(reply)) => reply,
+        Ok(None) => return Ok(()),
+        Err(e) => {
+            warn_file_api(context, &e);
+            return Ok(());
+        }
+    };
+    let Some(configuration) = reply.configuration(context.variant.build_type()) else {
+        return Ok(());
+    };
+    let project_targets: Vec<&str> = configuration
+        .targets
+        .iter()
+        .map(|target| target.name.as_str())
+        .collect();
+    let known_targets: Vec<&str> = project_targets
+        .iter()
+        .copied()
+        .chain(BUILTIN_TARGETS)
+        .collect();
+
+    for target in &context.targets {
+        if known_targets.contains(&target.as_str()) {
+            continue;
+        }
+        let hint = match suggest(target, &known_targets) {
+            Some(suggestion) => format!(", did you mean '{}'?", suggestion),
+            None => format!(", known targets: {}", project_targets.join(", ")),
+        };
+        return Err(BuildError::UnknownTarget {
+            message: format!(
+                "unknown target '{}' for variant {}{}",
+                target,
+                context.variant.as_str(),
+                hint
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Checks that the variant build directory, if already configured, was configured
+// with the requested generator. CMake refuses to reuse a cache created by another
+// generator, so the directory is either wiped (`--auto-clean`) or an error is returned.
+fn check_generator_mismatch(build_dir: &Path, context: &AppContext) -> Result<(), BuildError> {
+    let cached_generator = match UtilityHelper::fetch_cache_generator(build_dir) {
+        Some(generator) => generator,
+        None => return Ok(()),
+    };
+
+    if cached_generator == context.generator {
+        return Ok(());
+    }
+
+    if context.auto_clean {
+        let msg = format!(
+            "Build directory {} was configured with generator \"{}\", cleaning it for \"{}\".",
+            build_dir.display(),
+            cached_generator,
+            context.generator
+        );
+        UtilityHelper::print_status(msg.yellow().bold(), context.status_to_stderr);
+        UtilityHelper::delete_directory(&build_dir.to_path_buf())?;
+        return Ok(());
+    }
+
+    Err(BuildError::GeneratorMismatch {
+        build_dir: build_dir.to_path_buf(),
+        cached: cached_generator,
+        requested: context.generator.clone(),
+    })
+}
+
 // This function runs CMake commands to configure, build, and install a project.
-fn run_cmake(context: &AppContext) -> Result<(), Box<dyn Error>> {
+fn run_cmake(context: &AppContext) -> Result<(), BuildError> {
     let build_variant_dirpath = &context.build_dir.join(context.variant.as_str());
     let install_variant_dirpath = &context.install_dir.join(context.variant.as_str());
 
-    return UtilityHelper::current_working_directory(&build_variant_dirpath, || {
-        if context.has_stage(Stage::Configure) {
-            let command_format = format!(
-                "cmake \
-                 -DCMAKE_INSTALL_PREFIX={install_dir} \
-                 -DCMAKE_BUILD_TYPE={variant} \
-                 -DCMAKE_EXPORT_COMPILE_COMMANDS={value} \
-                 -G {generator} \
-                 {userConfigureArgs} \
-                 {extraArgs} \
-                 {srcDir} \
-                ",
-                install_dir = install_variant_dirpath.display(),
-                variant = &context.variant.as_str(),
-                value = "ON",
-                generator = &context.generator,
-                userConfigureArgs = UtilityHelper::stringify(&context.configure_args),
-                extraArgs = UtilityHelper::stringify(&context.extra_args),
-                srcDir = &context.project_location.display()
-            );
-
-            println!("{}", command_format.green());
-
-            match run_command(&command_format, &context) {
-                Ok(_) => {}
-                Err(_) => exit(1),
-            }
+    if context.has_stage(Stage::Configure) {
+        let expand = |values, key: &str| {
+            JsonUtil::expand_variant_variables(values, key, &context.variant, build_variant_dirpath)
+        };
+        let configure_args = expand(&context.configure_args, "project_options")?;
+        let extra_args = expand(&context.extra_args, "extra_args")?;
+        let variant_args = expand(
+            &context.variant.configure_args,
+            &format!("variants.{}.configure_args", context.variant.as_str()),
+        )?;
+
+        let mut command = BuildCommand::new("cmake");
+        command
+            .current_dir(build_variant_dirpath)
+            .arg(format!(
+                "-DCMAKE_INSTALL_PREFIX={}",
+                install_variant_dirpath.display()
+            ))
+            .arg(format!(
+                "-DCMAKE_BUILD_TYPE={}",
+                context.variant.build_type()
+            ))
+            .arg("-DCMAKE_EXPORT_COMPILE_COMMANDS=ON")
+            .arg("-G")
+            .arg(&context.generator)
+            .args(UtilityHelper::to_arguments(&configure_args))
+            .args(UtilityHelper::to_arguments(&extra_args))
+            .args(UtilityHelper::to_arguments(&variant_args));
+        // without a `-D` the cache keeps the value of an unset option from the last configure
+        for name in &context.unset_options {
+            command.arg("-U").arg(name);
         }
+        command
+            .args(&context.arguments.configure)
+            .arg("-S")
+            .arg(&context.project_location)
+            .arg("-B")
+            .arg(build_variant_dirpath);
 
-        if context.has_stage(Stage::Build) || context.has_stage(Stage::Install) {
-            let multiproc = format!("-j {}", num_cpus::get());
+        UtilityHelper::print_status(command.to_string().green(), context.status_to_stderr);
 
-            let command_format = format!(
-                "cmake --build . --config {variant} {installArg} -- {multiproc}",
-                variant = &context.variant.as_str(),
-                installArg = if context.has_stage(Stage::Install) {
-                    "--target install"
-                } else {
-                    ""
-                },
-                multiproc = multiproc
-            );
+        write_query(build_variant_dirpath)?;
+        let status = run_command(&command, context)?;
+        if !status.success() {
+            return Err(BuildError::ConfigureFailed {
+                code: status.code(),
+            });
+        }
 
-            match run_command(&command_format, &context) {
-                Ok(_) => {}
-                Err(_) => exit(1),
-            }
+        // the configure succeeded, a reply build-it can't read doesn't fail it
+        match context.file_api() {
+            Ok(Some(reply)) => print_code_model(&reply, context),
+            Ok(None) => {}
+            Err(e) => warn_file_api(context, &e),
+        }
+    }
 
-            if context.has_stage(Stage::Build) {
-                let colored_build_variant_dirpath =
-                    format!("Build Success: {}", build_variant_dirpath.display())
-                        .green()
-                        .bold();
-                println!("{}", colored_build_variant_dirpath);
-            }
+    if context.has_stage(Stage::Build) {
+        let jobs = context.jobs;
+        let mut native_args = native_parallel_args(&context.generator, jobs);
+
+        let mut command = BuildCommand::new("cmake");
+        command
+            .current_dir(build_variant_dirpath)
+            .arg("--build")
+            .arg(build_variant_dirpath)
+            .args(["--config", context.variant.build_type()]);
+        if !context.targets.is_empty() {
+            check_targets(context)?;
+            command.arg("--target").args(&context.targets);
+        }
+        command.args(&context.arguments.build);
+        if native_args.is_empty() {
+            // let cmake pick the parallel flag of the native tool itself
+            command.env("CMAKE_BUILD_PARALLEL_LEVEL", jobs.to_string());
+        }
+        // user arguments come last so they can override the parallel flag
+        native_args.extend(context.arguments.native_tool.iter().cloned());
+        if !native_args.is_empty() {
+            command.arg("--").args(native_args);
+        }
 
-            if context.has_stage(Stage::Install) {
-                let colored_install_variant_dirpath =
-                    format!("Install Success: {}", install_variant_dirpath.display())
-                        .green()
-                        .bold();
-                println!("{}", colored_install_variant_dirpath);
+        let status = run_command(&command, context)?;
+        if !status.success() {
+            return Err(BuildError::BuildFailed {
+                code: status.code(),
+            });
+        }
+
+        let colored_build_variant_dirpath =
+            format!("Build Success: {}", build_variant_dirpath.display())
+                .green()
+                .bold();
+        UtilityHelper::print_status(colored_build_variant_dirpath, context.status_to_stderr);
+    }
+
+    if context.has_stage(Stage::Install) {
+        let install_prefix = context
+            .install_prefix
+            .as_ref()
+            .unwrap_or(install_variant_dirpath);
+        run_install(build_variant_dirpath, install_prefix, context)?;
+
+        let colored_install_variant_dirpath =
+            format!("Install Success: {}", install_prefix.display())
+                .green()
+                .bold();
+        UtilityHelper::print_status(colored_install_variant_dirpath, context.status_to_stderr);
+    }
+
+    if context.has_stage(Stage::Test) {
+        run_ctest(build_variant_dirpath, context)?;
+    }
+
+    if context.has_stage(Stage::Package) {
+        let package_variant_dirpath = context.package_dir.join(context.variant.as_str());
+        UtilityHelper::create_new_directory(&package_variant_dirpath)?;
+        run_cpack(build_variant_dirpath, &package_variant_dirpath, context)?;
+    }
+
+    Ok(())
+}
+
+// The outcome of running the pipeline for one variant.
+struct VariantResult {
+    name: String,
+    result: Result<(), BuildError>,
+    elapsed: Duration,
+}
+
+// Creates the variant directories and runs the requested stages for `context.variant`.
+fn run_variant_stages(context: &AppContext) -> Result<(), BuildError> {
+    let build_variant_dir = &context.build_dir.join(context.variant.as_str());
+    let install_variant_dir = &context.install_dir.join(context.variant.as_str());
+
+    check_generator_mismatch(build_variant_dir, context)?;
+
+    for dir in [build_variant_dir, install_variant_dir] {
+        UtilityHelper::create_new_directory(dir)?;
+    }
+
+    run_cmake(context)
+}
+
+// Runs the pipeline for `context.variant` and records how it went.
+fn build_variant(context: &AppContext) -> VariantResult {
+    let start_time = Instant::now();
+    let result = run_variant_stages(context);
+
+    VariantResult {
+        name: context.variant.as_str().to_string(),
+        result,
+        elapsed: start_time.elapsed(),
+    }
+}
+
+// Prints one line per variant with its outcome and how long it took.
+fn print_variant_summary(results: &[VariantResult]) {
+    let width = results.iter().map(|r| r.name.len()).max().unwrap_or(0);
+
+    println!("{}", "Variant Summary".blue().bold());
+    for variant_result in results {
+        let elapsed = UtilityHelper::format_duration(variant_result.elapsed);
+        let line = match &variant_result.result {
+            Ok(_) => format!(
+                "\t{:<width$}  {:<7}  {}",
+                variant_result.name, "Success", elapsed
+            )
+            .green()
+            .bold(),
+            Err(e) => format!(
+                "\t{:<width$}  {:<7}  {}  {}",
+                variant_result.name, "Failed", elapsed, e
+            )
+            .red()
+            .bold(),
+        };
+        println!("{}", line);
+    }
+}
+
+// Whether `value` is a path inside one of `dirs`. `dirs` are compared as given and resolved,
+// cmake writes normalized paths into the cache.
+fn is_inside(value: &str, dirs: &[PathBuf]) -> bool {
+    let value = Path::new(value);
+    value.is_absolute()
+        && dirs.iter().any(|dir| {
+            value.starts_with(dir) || dir.canonicalize().is_ok_and(|dir| value.starts_with(dir))
+        })
+}
+
+// Lists the cache entries the project defines that project_settings.json doesn't know
+// about yet, once the variants are configured, and adds them to `project_options` when
+// asked to (`--sync-cache` or a yes at the prompt). Entries keep their type, e.g.
+// "-DFOO_DIR:PATH". Advanced entries, cmake's own `CMAKE_*` entries and names with a `:`,
+// which `-D` can't set, are left out, as are paths into the directories of a variant since
+// `project_options` apply to every variant. Only the caches of `variants` are read.
+fn sync_cache_entries(context: &AppContext, variants: &[&Variant]) -> Result<(), BuildError> {
+    let mut new_entries: IndexMap<String, CacheEntry> = IndexMap::new();
+    for variant in variants {
+        let variant_dirs = [
+            context.build_dir.join(variant.as_str()),
+            context.install_dir.join(variant.as_str()),
+        ];
+        let Some(entries) = read_cache(&variant_dirs[0]) else {
+            continue;
+        };
+        for entry in entries {
+            let known = context
+                .configure_args
+                .keys()
+                .chain(context.extra_args.keys())
+                .chain(variant.configure_args.keys())
+                .any(|key| is_option_key(key, &entry.name));
+            if entry.is_user_facing()
+                && !entry.advanced
+                && !entry.name.starts_with("CMAKE_")
+                && !entry.name.contains(':')
+                && !is_inside(&entry.value, &variant_dirs)
+                && !known
+            {
+                new_entries.entry(entry.name.clone()).or_insert(entry);
             }
         }
+    }
+    if new_entries.is_empty() {
+        return Ok(());
+    }
+
+    let defines: Vec<(String, String)> = new_entries
+        .values()
+        .map(|entry| {
+            (
+                format!("{}:{}", entry.name, entry.kind),
+                // keep the value from being read as a settings variable
+                entry.value.replace("${", "$${"),
+            )
+        })
+        .collect();
+
+    println!("{}", "New cache entries".blue().bold());
+    for ((name, _), entry) in defines.iter().zip(new_entries.values()) {
+        println!(
+            "\t{}={}  {}",
+            option_key(name).cyan(),
+            entry.value,
+            entry.help.dimmed()
+        );
+    }
+
+    let sync = context.sync_cache
+        || UtilityHelper::prompt(
+            &format!(
+                "Add {} cache entries to project_options? [y/N] ",
+                defines.len()
+            ),
+            context.status_to_stderr,
+        )?
+        .is_some_and(|answer| {
+            answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes")
+        });
+    if !sync {
+        println!(
+            "{}",
+            "Pass --sync-cache to add them to project_settings json.".yellow()
+        );
+        return Ok(());
+    }
+    JsonUtil::save_project_options(
+        &context.settings_file,
+        &defines,
+        &[],
+        context.status_to_stderr,
+    )
+}
+
+// This is the entry point function for building a cmake project. The requested variants
+// are built one after another, or concurrently with the CPU cores split between them.
+pub fn build_project(context: &AppContext) -> Result<(), BuildError> {
+    if context.has_stage(Stage::Clean) {
+        UtilityHelper::delete_directory(&context.build_dir)?;
+    }
+
+    UtilityHelper::create_new_directory(&context.workspace_dir)?;
+
+    let results: Vec<VariantResult> = if context.parallel_variants {
+        let jobs = (context.jobs / context.variants.len()).max(1);
+        thread::scope(|scope| {
+            let handles: Vec<_> = context
+                .variants
+                .iter()
+                .map(|variant| {
+                    let variant_context = context.for_variant(variant, jobs);
+                    scope.spawn(move || build_variant(&variant_context))
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("variant build thread panicked"))
+                .collect()
+        })
+    } else {
+        context
+            .variants
+            .iter()
+            .map(|variant| build_variant(&context.for_variant(variant, context.jobs)))
+            .collect()
+    };
+
+    if results.len() > 1 {
+        print_variant_summary(&results);
+    }
 
+    // a failed configure may have left a half-written cache behind
+    let succeeded: Vec<&Variant> = context
+        .variants
+        .iter()
+        .zip(&results)
+        .filter(|(_, result)| result.result.is_ok())
+        .map(|(variant, _)| variant)
+        .collect();
+    let synced = if context.has_stage(Stage::Configure) {
+        sync_cache_entries(context, &succeeded)
+    } else {
         Ok(())
-    });
+    };
+
+    // report the first failure, its exit code tells which stage failed
+    match results.into_iter().find_map(|r| r.result.err()) {
+        Some(e) => Err(e),
+        None => synced,
+    }
 }
 
-// This is the entry point function for building a cmake project.
-pub fn build_project(context: &AppContext) -> Result<(), Box<dyn Error>> {
-    return UtilityHelper::current_working_directory(&context.project_location, || {
-        if context.has_stage(Stage::Clean) {
-            UtilityHelper::delete_directory(&context.build_dir)?;
+// Returns the file API reply of `context.variant`, configuring the variant first if cmake
+// hasn't described it yet.
+fn configured_reply(context: &AppContext) -> Result<FileApiReply, BuildError> {
+    let mut reply = context.file_api()?;
+    if reply.is_none() {
+        let msg = format!(
+            "Variant {} is not configured yet, configuring it first (log: {})",
+            context.variant.as_str(),
+            context.build_log_location(&context.variant).display()
+        );
+        UtilityHelper::print_status(msg.yellow(), context.status_to_stderr);
+        let configure_context = AppContext {
+            stages: Box::new([Stage::Configure]),
+            ..context.clone()
+        };
+        run_variant_stages(&configure_context)?;
+        reply = context.file_api()?;
+    }
+    let reply = reply.ok_or_else(|| {
+        std::io::Error::other(
+            "cmake didn't answer the file API query, cmake 3.14 or newer is needed",
+        )
+    })?;
+    Ok(reply)
+}
+
+// Prints the targets of `context.variant` as a table, or as JSON with `json`. The variant is
+// configured first if cmake hasn't described it through the file API yet.
+pub fn list_targets(context: &AppContext, json: bool) -> Result<(), BuildError> {
+    let reply = configured_reply(context)?;
+    let targets = reply
+        .configuration(context.variant.build_type())
+        .map_or(&[][..], |configuration| &configuration.targets);
+
+    if json {
+        let targets: Vec<Value> = targets
+            .iter()
+            .map(|target| {
+                json!({
+                    "name": target.name,
+                    "type": target.kind.to_string(),
+                    "source_dir": target.paths.source,
+                    "artifacts": target.artifacts.iter().map(|artifact| &artifact.path).collect::<Vec<_>>(),
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&targets)?);
+        return Ok(());
+    }
+
+    let header = ["Target", "Type", "Source directory", "Artifacts"];
+    let rows: Vec<[String; 4]> = targets
+        .iter()
+        .map(|target| {
+            let source_dir = target
+                .paths
+                .source
+                .strip_prefix(&context.project_location)
+                .unwrap_or(&target.paths.source);
+            [
+                target.name.clone(),
+                target.kind.to_string(),
+                if source_dir.as_os_str().is_empty() {
+                    String::from(".")
+                } else {
+                    source_dir.display().to_string()
+                },
+                if target.artifacts.is_empty() {
+                    String::from("-")
+                } else {
+                    target
+                        .artifacts
+                        .iter()
+                        .map(|artifact| artifact.path.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                },
+            ]
+        })
+        .collect();
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
         }
+    }
 
-        // create workspace, build, install directories
-        let build_variant_dir = &context.build_dir.join(context.variant.as_str());
-        let install_variant_dir = &context.install_dir.join(context.variant.as_str());
+    let line = |cells: [&str; 4]| {
+        format!(
+            "{:<w0$}  {:<w1$}  {:<w2$}  {}",
+            cells[0],
+            cells[1],
+            cells[2],
+            cells[3],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2]
+        )
+    };
+    println!(
+        "{}",
+        format!("Targets of {}", context.variant.as_str())
+            .blue()
+            .bold()
+    );
+    println!("{}", line(header).bold());
+    for row in &rows {
+        println!("{}", line([&row[0], &row[1], &row[2], &row[3]]));
+    }
+    Ok(())
+}
 
-        for dir in [
-            &context.workspace_dir,
-            &build_variant_dir,
-            &install_variant_dir,
-        ] {
-            UtilityHelper::create_new_directory(dir)?;
+// Builds the executable target `name` of `context.variant` and runs it with `args`, with the
+// variant's install lib directory on LD_LIBRARY_PATH. Returns the exit code of the program.
+pub fn run_target(
+    context: &AppContext,
+    name: &str,
+    args: &[String],
+) -> Result<ExitCode, BuildError> {
+    let reply = configured_reply(context)?;
+    let targets = reply
+        .configuration(context.variant.build_type())
+        .map_or(&[][..], |configuration| &configuration.targets);
+    let executables: Vec<&str> = targets
+        .iter()
+        .filter(|target| target.kind == TargetKind::Executable)
+        .map(|target| target.name.as_str())
+        .collect();
+    let target = match targets.iter().find(|target| target.name == name) {
+        Some(target) if target.kind == TargetKind::Executable => target,
+        found => {
+            let problem = match found {
+                Some(target) => format!("target '{}' is a {}", name, target.kind),
+                None => format!(
+                    "unknown target '{}' for variant {}",
+                    name,
+                    context.variant.as_str()
+                ),
+            };
+            let hint = match suggest(name, &executables) {
+                Some(suggestion) => format!(", did you mean '{}'?", suggestion),
+                None => format!(", executable targets: {}", executables.join(", ")),
+            };
+            return Err(BuildError::UnknownTarget {
+                message: format!("{}{}", problem, hint),
+            });
         }
+    };
+
+    let msg = format!(
+        "Building {} of {} (log: {})",
+        name,
+        context.variant.as_str(),
+        context.build_log_location(&context.variant).display()
+    );
+    UtilityHelper::print_status(msg.blue(), context.status_to_stderr);
+    let build_context = AppContext {
+        stages: Box::new([Stage::Build]),
+        targets: vec![name.to_string()],
+        ..context.clone()
+    };
+    run_variant_stages(&build_context)?;
 
-        run_cmake(&context)?;
+    // the build tree artifact, or the installed one if the build tree doesn't have it, looked
+    // up in every destination the project installs the target to
+    let install_variant_dirpath = context.install_dir.join(context.variant.as_str());
+    let install_prefix = context
+        .install_prefix
+        .as_ref()
+        .unwrap_or(&install_variant_dirpath);
+    let build_artifact = target
+        .artifacts
+        .first()
+        .map(|artifact| artifact.path.clone());
+    let install_artifacts: Vec<PathBuf> = match (&build_artifact, &target.install) {
+        (Some(path), Some(install)) => match path.file_name() {
+            Some(file_name) => install
+                .destinations
+                .iter()
+                .map(|destination| install_prefix.join(&destination.path).join(file_name))
+                .collect(),
+            None => vec![],
+        },
+        _ => vec![],
+    };
+    let executable = build_artifact
+        .into_iter()
+        .chain(install_artifacts)
+        .find(|path| path.is_file())
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!(
+                    "no artifact of target '{}' in the build or install tree of {}",
+                    name,
+                    context.variant.as_str()
+                ),
+            )
+        })?;
 
-        Ok(())
-    });
+    let mut library_path = vec![install_prefix.join("lib")];
+    if let Some(existing) = env::var_os(LIBRARY_PATH_VAR) {
+        library_path.extend(env::split_paths(&existing));
+    }
+    let mut command = BuildCommand::new(&executable.to_string_lossy());
+    command.args(args).env(
+        LIBRARY_PATH_VAR,
+        env::join_paths(library_path).map_err(std::io::Error::other)?,
+    );
+    UtilityHelper::print_status(
+        format!("Running {}", command).green(),
+        context.status_to_stderr,
+    );
+
+    let status = command
+        .to_command()
+        .status()
+        .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", executable.display(), e)))?;
+    Ok(ExitCode::from(program_exit_code(status)))
+}
+
+// Returns the exit code of a program that ran with `status`. A program killed by a signal has
+// none, it gets 128 + the signal number like in a shell.
+fn program_exit_code(status: ExitStatus) -> u8 {
+    if let Some(code) = status.code() {
+        return u8::try_from(code).unwrap_or(1);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return u8::try_from(128 + signal).unwrap_or(1);
+        }
+    }
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(passed: usize, failed: usize, skipped: usize) -> TestSummary {
+        TestSummary {
+            passed,
+            failed,
+            skipped,
+        }
+    }
+
+    #[test]
+    fn counts_passing_tests() {
+        let log = "\
+Test project /work/build/Debug
+    Start 1: unit_tests
+1/2 Test #1: unit_tests .......................   Passed    0.01 sec
+    Start 2: integration
+2/2 Test #2: integration ......................   Passed    1.20 sec
+
+100% tests passed, 0 tests failed out of 2
+";
+        assert_eq!(parse_ctest_log(log), summary(2, 0, 0));
+    }
+
+    #[test]
+    fn counts_failing_and_skipped_tests() {
+        let log = "\
+    Start 1: unit_tests
+1/4 Test #1: unit_tests .......................   Passed    0.01 sec
+    Start 2: broken
+2/4 Test #2: broken ...........................***Failed    0.00 sec
+    Start 3: crashing
+3/4 Test #3: crashing .........................***Exception: SegFault  0.02 sec
+    Start 4: optional
+4/4 Test #4: optional .........................***Skipped   0.00 sec
+
+50% tests passed, 2 tests failed out of 4
+
+The following tests FAILED:
+\t  2 - broken (Failed)
+\t  3 - crashing (SEGFAULT)
+";
+        assert_eq!(parse_ctest_log(log), summary(1, 2, 1));
+    }
+
+    #[test]
+    fn counts_timed_out_tests_as_failed() {
+        let log = "\
+    Start 1: slow.tests
+1/2 Test #1: slow.tests .......................***Timeout   1.50 sec
+    Start 2: disabled
+2/2 Test #2: disabled .........................***Not Run (Disabled)   0.00 sec
+";
+        assert_eq!(parse_ctest_log(log), summary(0, 1, 1));
+    }
+
+    #[test]
+    fn finds_the_packages_cpack_generated() {
+        let output: Vec<String> = [
+            "CPack: Create package using TGZ",
+            "CPack: Install projects",
+            "CPack: - package: /work/package/Debug/app-1.0-Linux.tar.gz generated.",
+            "CPack: - checksum file: /work/package/Debug/app-1.0-Linux.tar.gz.sha256 generated.",
+            "CPack: - package: /work/package/Debug/my app-1.0-Linux.zip generated.",
+        ]
+        .map(String::from)
+        .to_vec();
+        assert_eq!(
+            parse_cpack_packages(&output),
+            [
+                PathBuf::from("/work/package/Debug/app-1.0-Linux.tar.gz"),
+                PathBuf::from("/work/package/Debug/my app-1.0-Linux.zip"),
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_logs_without_results() {
+        assert_eq!(
+            parse_ctest_log("No tests were found!!!\n"),
+            summary(0, 0, 0)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exits_like_a_shell_for_a_killed_program() {
+        use std::os::unix::process::ExitStatusExt;
+        // wait status encoding: the exit code in the second byte, the signal in the first
+        assert_eq!(program_exit_code(ExitStatus::from_raw(3 << 8)), 3);
+        assert_eq!(program_exit_code(ExitStatus::from_raw(9)), 137);
+        assert_eq!(program_exit_code(ExitStatus::from_raw(11)), 139);
+    }
 }
@@ -0,0 +1,171 @@
+use std::fs;
+use std::path::Path;
+
+const CACHE_FILE_NAME: &str = "CMakeCache.txt";
+// cmake marks an advanced entry NAME with an internal `NAME-ADVANCED` entry
+const ADVANCED_SUFFIX: &str = "-ADVANCED";
+
+// An entry of the CMakeCache.txt of a configured build directory.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CacheEntry {
+    pub name: String,
+    // BOOL, STRING, PATH, FILEPATH, INTERNAL, STATIC or UNINITIALIZED
+    pub kind: String,
+    pub value: String,
+    // the `//` comment lines above the entry, joined with spaces
+    pub help: String,
+    // hidden by cmake-gui and ccmake unless advanced entries are shown
+    pub advanced: bool,
+}
+
+impl CacheEntry {
+    // Whether the entry is meant to be set by users rather than by cmake itself.
+    pub fn is_user_facing(&self) -> bool {
+        !matches!(self.kind.as_str(), "INTERNAL" | "STATIC")
+    }
+}
+
+// Splits `NAME:TYPE=VALUE` into its parts, the name may be quoted if it contains a `:`.
+fn parse_entry(line: &str) -> Option<(String, String, String)> {
+    let (name, rest) = match line.strip_prefix('"') {
+        Some(quoted) => {
+            let (name, rest) = quoted.split_once('"')?;
+            (name, rest.strip_prefix(':')?)
+        }
+        None => line.split_once(':')?,
+    };
+    let (kind, value) = rest.split_once('=')?;
+    Some((name.to_string(), kind.to_string(), value.to_string()))
+}
+
+// Parses the contents of a CMakeCache.txt, in file order.
+pub fn parse_cache(contents: &str) -> Vec<CacheEntry> {
+    let mut entries: Vec<CacheEntry> = Vec::new();
+    let mut advanced = Vec::new();
+    let mut help: Vec<&str> = Vec::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if let Some(comment) = line.strip_prefix("//") {
+            help.push(comment.trim());
+            continue;
+        }
+        if line.is_empty() || line.starts_with('#') {
+            help.clear();
+            continue;
+        }
+        if let Some((name, kind, value)) = parse_entry(line) {
+            match name.strip_suffix(ADVANCED_SUFFIX) {
+                Some(advanced_name) if kind == "INTERNAL" => {
+                    if value == "1" {
+                        advanced.push(advanced_name.to_string());
+                    }
+                }
+                _ => entries.push(CacheEntry {
+                    name,
+                    kind,
+                    value,
+                    help: help.join(" "),
+                    advanced: false,
+                }),
+            }
+        }
+        help.clear();
+    }
+
+    for entry in &mut entries {
+        entry.advanced = advanced.contains(&entry.name);
+    }
+    entries
+}
+
+// Reads the cache of `build_dir`. Returns None if the directory has not been configured yet.
+pub fn read_cache(build_dir: &Path) -> Option<Vec<CacheEntry>> {
+    let contents = fs::read_to_string(build_dir.join(CACHE_FILE_NAME)).ok()?;
+    Some(parse_cache(&contents))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, kind: &str, value: &str, help: &str, advanced: bool) -> CacheEntry {
+        CacheEntry {
+            name: name.to_string(),
+            kind: kind.to_string(),
+            value: value.to_string(),
+            help: help.to_string(),
+            advanced,
+        }
+    }
+
+    #[test]
+    fn joins_the_help_lines_of_an_entry() {
+        let cache = "\
+//Path to the Foo SDK,
+// used by the importers.
+FOO_SDK_DIR:PATH=/opt/foo
+
+BUILD_TESTS:BOOL=ON
+";
+        assert_eq!(
+            parse_cache(cache),
+            [
+                entry(
+                    "FOO_SDK_DIR",
+                    "PATH",
+                    "/opt/foo",
+                    "Path to the Foo SDK, used by the importers.",
+                    false
+                ),
+                entry("BUILD_TESTS", "BOOL", "ON", "", false),
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_quoted_names() {
+        let cache = "\"WITH:COLON\":STRING=a=b\n\"UNTERMINATED:STRING=x\n";
+        // the value keeps everything after the first `=`, a broken line is skipped
+        assert_eq!(
+            parse_cache(cache),
+            [entry("WITH:COLON", "STRING", "a=b", "", false)]
+        );
+    }
+
+    #[test]
+    fn marks_advanced_entries() {
+        let cache = "\
+CMAKE_AR:FILEPATH=/usr/bin/ar
+LOG_LEVEL:STRING=info
+//ADVANCED property for variable: CMAKE_AR
+CMAKE_AR-ADVANCED:INTERNAL=1
+LOG_LEVEL-ADVANCED:INTERNAL=0
+";
+        assert_eq!(
+            parse_cache(cache),
+            [
+                entry("CMAKE_AR", "FILEPATH", "/usr/bin/ar", "", true),
+                entry("LOG_LEVEL", "STRING", "info", "", false),
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_comments_and_forgets_help_above_them() {
+        let cache = "\
+# This is the CMakeCache file.
+//Dangling help
+########################
+# EXTERNAL cache entries
+########################
+
+CMAKE_GENERATOR:INTERNAL=Ninja
+";
+        let entries = parse_cache(cache);
+        assert_eq!(
+            entries,
+            [entry("CMAKE_GENERATOR", "INTERNAL", "Ninja", "", false)]
+        );
+        assert!(!entries[0].is_user_facing());
+    }
+}
@@ -0,0 +1,682 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+// A reader for the CMake language, see
+// https://cmake.org/cmake/help/latest/manual/cmake-language.7.html
+// It splits a listfile into command invocations and their arguments without evaluating
+// them, which is enough to discover the options and the name of a project.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArgumentKind {
+    Unquoted,
+    Quoted,
+    Bracket,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Argument {
+    // the value with escape sequences and line continuations resolved,
+    // variable references like `${NAME}` are kept as written
+    pub value: String,
+    pub kind: ArgumentKind,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CommandInvocation {
+    // lowercase, command names are case-insensitive
+    pub name: String,
+    pub arguments: Vec<Argument>,
+    // 1-based line of the command name
+    pub line: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.position + offset).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.position += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn error<T>(&self, line: usize, message: String) -> Result<T, ParseError> {
+        Err(ParseError { line, message })
+    }
+
+    // Returns the number of `=` of a bracket opening `[==[` at the current position.
+    fn bracket_open_length(&self) -> Option<usize> {
+        if self.peek() != Some('[') {
+            return None;
+        }
+        let mut length = 0;
+        while self.peek_at(1 + length) == Some('=') {
+            length += 1;
+        }
+        (self.peek_at(1 + length) == Some('[')).then_some(length)
+    }
+
+    // Reads the contents of a bracket argument or comment, the opening is at the current position.
+    fn bracket_contents(&mut self, length: usize) -> Result<String, ParseError> {
+        let start_line = self.line;
+        self.position += length + 2;
+        // a newline right after the opening bracket is not part of the contents
+        if self.peek() == Some('\r') && self.peek_at(1) == Some('\n') {
+            self.position += 1;
+        }
+        if self.peek() == Some('\n') {
+            self.bump();
+        }
+
+        let mut contents = String::new();
+        loop {
+            match self.bump() {
+                Some(']')
+                    if (0..length).all(|i| self.peek_at(i) == Some('='))
+                        && self.peek_at(length) == Some(']') =>
+                {
+                    self.position += length + 1;
+                    return Ok(contents);
+                }
+                Some(c) => contents.push(c),
+                None => {
+                    return self.error(
+                        start_line,
+                        format!("unterminated bracket `[{}[`", "=".repeat(length)),
+                    )
+                }
+            }
+        }
+    }
+
+    // Skips a `#` line comment or `#[[ ]]` bracket comment.
+    fn skip_comment(&mut self) -> Result<(), ParseError> {
+        self.bump();
+        if let Some(length) = self.bracket_open_length() {
+            self.bracket_contents(length)?;
+            return Ok(());
+        }
+        while let Some(c) = self.peek() {
+            if c == '\n' {
+                break;
+            }
+            self.bump();
+        }
+        Ok(())
+    }
+
+    // Resolves the escape sequence after a `\`. Returns None for a line continuation.
+    fn escape_sequence(&mut self) -> Result<Option<String>, ParseError> {
+        let line = self.line;
+        let escaped = match self.bump() {
+            Some('n') => "\n".to_string(),
+            Some('t') => "\t".to_string(),
+            Some('r') => "\r".to_string(),
+            // `\;` keeps a semicolon from separating list elements
+            Some(';') => "\\;".to_string(),
+            Some('\n') => return Ok(None),
+            Some('\r') if self.peek() == Some('\n') => {
+                self.bump();
+                return Ok(None);
+            }
+            Some(c) if c.is_ascii_alphanumeric() => {
+                return self.error(line, format!("invalid escape sequence `\\{}`", c))
+            }
+            Some(c) => c.to_string(),
+            None => return self.error(line, "unexpected end of file after `\\`".to_string()),
+        };
+        Ok(Some(escaped))
+    }
+
+    fn quoted_argument(&mut self) -> Result<String, ParseError> {
+        let start_line = self.line;
+        self.bump();
+        let mut value = String::new();
+        loop {
+            match self.bump() {
+                Some('"') => return Ok(value),
+                Some('\\') => {
+                    if let Some(escaped) = self.escape_sequence()? {
+                        value.push_str(&escaped);
+                    }
+                }
+                Some(c) => value.push(c),
+                None => return self.error(start_line, "unterminated quoted argument".to_string()),
+            }
+        }
+    }
+
+    fn unquoted_argument(&mut self) -> Result<String, ParseError> {
+        let mut value = String::new();
+        while let Some(c) = self.peek() {
+            match c {
+                c if c.is_whitespace() => break,
+                '(' | ')' | '#' => break,
+                '\\' => {
+                    self.bump();
+                    // a line continuation joins the argument with the next line, as in cmake
+                    if let Some(escaped) = self.escape_sequence()? {
+                        value.push_str(&escaped);
+                    }
+                }
+                // legacy unquoted arguments may contain quoted parts, e.g. -Da="b c"
+                '"' => {
+                    let part = self.quoted_argument()?;
+                    value.push('"');
+                    value.push_str(&part);
+                    value.push('"');
+                }
+                c => {
+                    self.bump();
+                    value.push(c);
+                }
+            }
+        }
+        Ok(value)
+    }
+
+    // Reads the arguments of a command invocation up to its closing parenthesis.
+    // Nested parentheses, as in `if((A OR B) AND C)`, are kept as `(` and `)` arguments.
+    fn arguments(&mut self, name: &str, start_line: usize) -> Result<Vec<Argument>, ParseError> {
+        let mut arguments = Vec::new();
+        let mut depth = 0;
+        loop {
+            let Some(c) = self.peek() else {
+                return self.error(start_line, format!("unterminated `{}(`", name));
+            };
+            let (value, kind) = match c {
+                c if c.is_whitespace() => {
+                    self.bump();
+                    continue;
+                }
+                '#' => {
+                    self.skip_comment()?;
+                    continue;
+                }
+                ')' if depth == 0 => {
+                    self.bump();
+                    return Ok(arguments);
+                }
+                '(' | ')' => {
+                    depth = if c == '(' { depth + 1 } else { depth - 1 };
+                    self.bump();
+                    (c.to_string(), ArgumentKind::Unquoted)
+                }
+                '"' => (self.quoted_argument()?, ArgumentKind::Quoted),
+                '[' if self.bracket_open_length().is_some() => {
+                    let length = self.bracket_open_length().unwrap_or_default();
+                    (self.bracket_contents(length)?, ArgumentKind::Bracket)
+                }
+                _ => (self.unquoted_argument()?, ArgumentKind::Unquoted),
+            };
+            arguments.push(Argument { value, kind });
+        }
+    }
+}
+
+// Splits the contents of a listfile into its command invocations, in file order.
+pub fn parse(source: &str) -> Result<Vec<CommandInvocation>, ParseError> {
+    // editors on Windows may start the file with a byte order mark
+    let source = source.strip_prefix('\u{feff}').unwrap_or(source);
+    let mut parser = Parser {
+        chars: source.chars().collect(),
+        position: 0,
+        line: 1,
+    };
+
+    let mut commands = Vec::new();
+    while let Some(c) = parser.peek() {
+        if c.is_whitespace() {
+            parser.bump();
+        } else if c == '#' {
+            parser.skip_comment()?;
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let line = parser.line;
+            let mut name = String::new();
+            while let Some(c) = parser.peek() {
+                if !(c.is_ascii_alphanumeric() || c == '_') {
+                    break;
+                }
+                name.push(c);
+                parser.bump();
+            }
+            while matches!(parser.peek(), Some(' ') | Some('\t')) {
+                parser.bump();
+            }
+            if parser.peek() != Some('(') {
+                return parser.error(line, format!("expected `(` after `{}`", name));
+            }
+            parser.bump();
+            let arguments = parser.arguments(&name, line)?;
+            commands.push(CommandInvocation {
+                name: name.to_ascii_lowercase(),
+                arguments,
+                line,
+            });
+        } else {
+            return parser.error(parser.line, format!("unexpected character `{}`", c));
+        }
+    }
+    Ok(commands)
+}
+
+// An option declared with `option()` or `cmake_dependent_option()`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProjectOption {
+    pub name: String,
+    // the default value, ON or OFF
+    pub value: String,
+    // the listfile and line declaring the option
+    pub file: PathBuf,
+    pub line: usize,
+}
+
+// Whether a CMake constant is true, see the `if(<constant>)` documentation.
+fn is_true_constant(value: &str) -> bool {
+    let value = value.to_ascii_uppercase();
+    ["ON", "YES", "TRUE", "Y"].contains(&value.as_str())
+        || value.parse::<f64>().is_ok_and(|number| number != 0.0)
+}
+
+// Tracks the variables set by plain `set()` and `list(APPEND)` calls, so an option whose
+// default is a variable can be resolved. Nothing else is evaluated: an undefined variable
+// is empty, as in CMake, and the project being scanned is the top level project.
+#[derive(Clone, Default)]
+pub struct Variables {
+    values: HashMap<String, String>,
+}
+
+impl Variables {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+
+    pub fn define(&mut self, name: &str, value: &str) {
+        self.values.insert(name.to_string(), value.to_string());
+    }
+
+    // Expands the `${NAME}` references in `value`.
+    pub fn expand(&self, value: &str) -> String {
+        let mut expanded = String::new();
+        let mut rest = value;
+        while let Some(start) = rest.find("${") {
+            let Some(end) = rest[start..].find('}') else {
+                break;
+            };
+            expanded.push_str(&rest[..start]);
+            let name = &rest[start + 2..start + end];
+            match self.values.get(name) {
+                Some(value) => expanded.push_str(value),
+                None if name.ends_with("_IS_TOP_LEVEL") => expanded.push_str("ON"),
+                None => {}
+            }
+            rest = &rest[start + end + 1..];
+        }
+        expanded.push_str(rest);
+        expanded
+    }
+
+    // Expands `arguments` up to the first of `keywords` and joins them into a list.
+    fn expand_list(&self, arguments: &[Argument], keywords: &[&str]) -> Vec<String> {
+        arguments
+            .iter()
+            .take_while(|argument| !keywords.contains(&argument.value.as_str()))
+            .map(|argument| self.expand(&argument.value))
+            .collect()
+    }
+
+    // set(<variable> <value>... [CACHE <type> <docstring>] [PARENT_SCOPE])
+    fn set(&mut self, arguments: &[Argument]) {
+        let Some((name, values)) = arguments.split_first() else {
+            return;
+        };
+        let name = self.expand(&name.value);
+        let values = self.expand_list(values, &["CACHE", "PARENT_SCOPE"]);
+        if values.is_empty() {
+            self.values.remove(&name);
+        } else {
+            self.values.insert(name, values.join(";"));
+        }
+    }
+
+    // list(APPEND <list> <element>...)
+    fn list(&mut self, arguments: &[Argument]) {
+        let [operation, name, elements @ ..] = arguments else {
+            return;
+        };
+        if operation.value != "APPEND" {
+            return;
+        }
+        let name = self.expand(&name.value);
+        let mut list: Vec<String> = self
+            .get(&name)
+            .filter(|list| !list.is_empty())
+            .map(|list| vec![list.to_string()])
+            .unwrap_or_default();
+        list.extend(self.expand_list(elements, &[]));
+        self.values.insert(name, list.join(";"));
+    }
+}
+
+// Evaluates the commands that define variables or options. Returns the option declared
+// by `command`, if any, `file` is the listfile it comes from.
+pub fn evaluate(
+    command: &CommandInvocation,
+    file: &Path,
+    variables: &mut Variables,
+) -> Option<ProjectOption> {
+    // option(<variable> "<help>" [value]) and
+    // cmake_dependent_option(<option> "<help>" <value> <depends> <force>)
+    let default = match command.name.as_str() {
+        "set" => {
+            variables.set(&command.arguments);
+            return None;
+        }
+        "list" => {
+            variables.list(&command.arguments);
+            return None;
+        }
+        "option" | "cmake_dependent_option" => command.arguments.get(2),
+        _ => return None,
+    };
+    let name = command.arguments.first()?;
+    let value = default.is_some_and(|value| is_true_constant(&variables.expand(&value.value)));
+    Some(ProjectOption {
+        name: variables.expand(&name.value),
+        value: if value { "ON" } else { "OFF" }.to_string(),
+        file: file.to_path_buf(),
+        line: command.line,
+    })
+}
+
+// Returns the name given to the first `project()` call in `commands`, expanding the variables
+// set before it.
+pub fn find_project_name(commands: &[CommandInvocation]) -> Option<String> {
+    let mut variables = Variables::default();
+    for command in commands {
+        match command.name.as_str() {
+            "set" => variables.set(&command.arguments),
+            "list" => variables.list(&command.arguments),
+            "project" => {
+                return command
+                    .arguments
+                    .first()
+                    .map(|name| variables.expand(&name.value))
+                    .filter(|name| !name.is_empty())
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(source: &str) -> Vec<(String, String)> {
+        let mut variables = Variables::default();
+        parse(source)
+            .unwrap()
+            .iter()
+            .filter_map(|command| evaluate(command, Path::new("CMakeLists.txt"), &mut variables))
+            .map(|option| (option.name, option.value))
+            .collect()
+    }
+
+    fn option(name: &str, value: &str) -> (String, String) {
+        (name.to_string(), value.to_string())
+    }
+
+    fn values(source: &str) -> Vec<String> {
+        parse(source).unwrap()[0]
+            .arguments
+            .iter()
+            .map(|argument| argument.value.clone())
+            .collect()
+    }
+
+    #[test]
+    fn parses_single_line_option() {
+        assert_eq!(
+            options(r#"option(BUILD_TESTS "Build the tests" ON)"#),
+            [option("BUILD_TESTS", "ON")]
+        );
+    }
+
+    #[test]
+    fn parses_multi_line_option() {
+        let source = "option(\n    WITH_DOCS\n    \"Build the\n documentation\"\n    ON\n)\n";
+        assert_eq!(options(source), [option("WITH_DOCS", "ON")]);
+    }
+
+    #[test]
+    fn option_without_help_or_value_defaults_to_off() {
+        let source = "option(NO_HELP)\noption(NO_VALUE \"help\")\n";
+        assert_eq!(
+            options(source),
+            [option("NO_HELP", "OFF"), option("NO_VALUE", "OFF")]
+        );
+    }
+
+    #[test]
+    fn normalizes_boolean_constants() {
+        let source = r#"
+            option(A "" TRUE)
+            option(B "" false)
+            option(C "" YES)
+            option(D "" No)
+            option(E "" 1)
+            option(F "" 0)
+            option(G "" y)
+        "#;
+        assert_eq!(
+            options(source),
+            [
+                option("A", "ON"),
+                option("B", "OFF"),
+                option("C", "ON"),
+                option("D", "OFF"),
+                option("E", "ON"),
+                option("F", "OFF"),
+                option("G", "ON"),
+            ]
+        );
+    }
+
+    #[test]
+    fn resolves_variable_defaults() {
+        let source = r#"
+            set(DEFAULT_ON ON)
+            set(DEFAULT_OFF OFF CACHE BOOL "")
+            option(A "" ${DEFAULT_ON})
+            option(B "" ${DEFAULT_OFF})
+            option(C "" ${UNDEFINED})
+            option(D "" ${PROJECT_IS_TOP_LEVEL})
+            set(DEFAULT_ON)
+            option(E "" ${DEFAULT_ON})
+        "#;
+        assert_eq!(
+            options(source),
+            [
+                option("A", "ON"),
+                option("B", "OFF"),
+                option("C", "OFF"),
+                option("D", "ON"),
+                option("E", "OFF"),
+            ]
+        );
+    }
+
+    #[test]
+    fn tracks_lists() {
+        let commands = parse(
+            "set(PATHS a b CACHE STRING \"\")\nlist(APPEND PATHS c)\nlist(APPEND EMPTY d)\nset(GONE x)\nset(GONE)",
+        )
+        .unwrap();
+        let mut variables = Variables::default();
+        for command in &commands {
+            evaluate(command, Path::new("CMakeLists.txt"), &mut variables);
+        }
+        assert_eq!(variables.get("PATHS"), Some("a;b;c"));
+        assert_eq!(variables.get("EMPTY"), Some("d"));
+        assert_eq!(variables.get("GONE"), None);
+    }
+
+    #[test]
+    fn skips_commented_out_options() {
+        let source = r#"
+            # option(LINE_COMMENT "" ON)
+            #[[
+            option(BRACKET_COMMENT "" ON)
+            ]]
+            #[==[ option(EQUALS_COMMENT "" ON) ]] still commented ]==]
+            option(KEPT "" ON) # option(TRAILING "" ON)
+        "#;
+        assert_eq!(options(source), [option("KEPT", "ON")]);
+    }
+
+    #[test]
+    fn parses_cmake_dependent_option() {
+        let source = r#"
+            include(CMakeDependentOption)
+            cmake_dependent_option(USE_FOO "Use foo" ON
+                                   "USE_BAR;NOT USE_ZOT" OFF)
+        "#;
+        assert_eq!(options(source), [option("USE_FOO", "ON")]);
+    }
+
+    #[test]
+    fn help_strings_may_contain_parentheses_and_quotes() {
+        let source = r#"
+            option(QUOTED "say \"hi\" (twice)" ON)
+            option(BRACKET [[a "raw" help ) string]] OFF)
+            option(EQUALS [=[contains ]] inside]=] ON)
+        "#;
+        assert_eq!(
+            options(source),
+            [
+                option("QUOTED", "ON"),
+                option("BRACKET", "OFF"),
+                option("EQUALS", "ON")
+            ]
+        );
+    }
+
+    #[test]
+    fn command_names_are_case_insensitive() {
+        assert_eq!(options("OPTION(UPPER \"\" ON)"), [option("UPPER", "ON")]);
+        assert_eq!(
+            parse("Project(Mixed)").unwrap()[0].name,
+            "project".to_string()
+        );
+    }
+
+    #[test]
+    fn resolves_escape_sequences_and_line_continuations() {
+        assert_eq!(
+            values("message(\"a\\tb\\\"c\\;d\\\n continued\" e\\ f)"),
+            ["a\tb\"c\\;d continued", "e f"]
+        );
+    }
+
+    #[test]
+    fn joins_unquoted_arguments_continued_on_the_next_line() {
+        assert_eq!(values("set(A abc\\\ndef ghi)"), ["A", "abcdef", "ghi"]);
+        assert_eq!(values("set(A abc\\\r\ndef)"), ["A", "abcdef"]);
+    }
+
+    #[test]
+    fn skips_a_byte_order_mark() {
+        let commands = parse("\u{feff}project(bom)\noption(A \"\" ON)").unwrap();
+        assert_eq!(commands.len(), 2);
+        assert_eq!(commands[0].name, "project");
+        assert_eq!(commands[0].line, 1);
+    }
+
+    #[test]
+    fn bracket_arguments_skip_the_first_newline() {
+        assert_eq!(values("message([[\nfirst\nsecond]])"), ["first\nsecond"]);
+    }
+
+    #[test]
+    fn keeps_nested_parentheses_and_legacy_quotes() {
+        assert_eq!(
+            values("if((A OR B) AND -Dx=\"y z\")"),
+            ["(", "A", "OR", "B", ")", "AND", "-Dx=\"y z\""]
+        );
+    }
+
+    #[test]
+    fn records_the_line_of_each_command() {
+        let commands = parse("# comment\nproject(p)\n\noption(\n  A \"\" ON)\n").unwrap();
+        let lines: Vec<usize> = commands.iter().map(|command| command.line).collect();
+        assert_eq!(lines, [2, 4]);
+    }
+
+    #[test]
+    fn finds_the_project_name() {
+        let find = |source: &str| find_project_name(&parse(source).unwrap());
+        assert_eq!(find("project(simple)"), Some("simple".to_string()));
+        assert_eq!(
+            find("cmake_minimum_required(VERSION 3.20)\nproject(\n  # the name\n  \"quoted name\"\n  VERSION 1.0\n  LANGUAGES CXX)"),
+            Some("quoted name".to_string())
+        );
+        assert_eq!(find("# project(commented)\nmessage(hi)"), None);
+
+        assert_eq!(
+            find("set(NAME from_variable)\nproject(${NAME})\nset(NAME too_late)"),
+            Some("from_variable".to_string())
+        );
+    }
+
+    #[test]
+    fn reports_errors_with_their_line() {
+        assert_eq!(
+            parse("message(ok)\nmessage(\"unterminated)\n").unwrap_err(),
+            ParseError {
+                line: 2,
+                message: "unterminated quoted argument".to_string()
+            }
+        );
+        assert_eq!(
+            parse("\n\nproject(p").unwrap_err(),
+            ParseError {
+                line: 3,
+                message: "unterminated `project(`".to_string()
+            }
+        );
+        assert_eq!(parse("set(x [==[ open").unwrap_err().line, 1);
+        assert_eq!(parse("oops").unwrap_err().line, 1);
+        assert_eq!(parse("message(\"\\q\")").unwrap_err().line, 1);
+    }
+}
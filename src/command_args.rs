@@ -1,4 +1,5 @@
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
+use indexmap::IndexMap;
 use std::path::PathBuf;
 
 #[derive(Copy, Clone, Debug, ValueEnum)]
@@ -7,6 +8,8 @@ pub enum Stage {
     Configure,
     Build,
     Install,
+    Test,
+    Package,
 }
 
 impl PartialEq for Stage {
@@ -21,32 +24,133 @@ fn parse_stages(arg: &str) -> StageResult {
     arg.split(',').map(|s| Stage::from_str(s, true)).collect()
 }
 
+// The build types CMake knows about, in the spelling CMake expects.
+const BUILD_TYPES: [&str; 4] = ["Debug", "Release", "RelWithDebInfo", "MinSizeRel"];
+
+// A build variant: one of CMake's build types, or a user-defined variant declared in
+// project_settings json that maps to a build type plus extra configure arguments.
+// Every variant gets its own build/install directories named after it.
 #[derive(Clone, Debug)]
-pub enum Variant {
-    Debug,
-    Release,
-    RelWithDebInfo,
+pub struct Variant {
+    name: String,
+    build_type: String,
+    pub configure_args: IndexMap<String, String>,
 }
 
 impl Variant {
-    pub fn as_str(&self) -> &str {
-        match self {
-            Variant::Debug => "Debug",
-            Variant::Release => "Release",
-            Variant::RelWithDebInfo => "RelWithDebInfo",
+    // Checks the name of a user-defined variant. It becomes a directory of the build and
+    // install trees, so it must not be a path.
+    pub fn check_name(name: &str) -> Result<(), String> {
+        if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+            return Err(format!(
+                "variant name '{}' can't be used as a directory name, \
+                 it must not be empty, `.`, `..` or contain `/` or `\\`",
+                name
+            ));
+        }
+        Ok(())
+    }
+
+    // Creates a user-defined variant. `build_type` is matched case-insensitively.
+    pub fn custom(
+        name: &str,
+        build_type: &str,
+        configure_args: IndexMap<String, String>,
+    ) -> Result<Variant, String> {
+        let build_type = BUILD_TYPES
+            .iter()
+            .find(|known| known.eq_ignore_ascii_case(build_type))
+            .ok_or_else(|| {
+                format!(
+                    "variant '{}' has unknown build type '{}', expected one of: {}",
+                    name,
+                    build_type,
+                    BUILD_TYPES.join(", ")
+                )
+            })?;
+        Ok(Variant {
+            name: name.to_string(),
+            build_type: build_type.to_string(),
+            configure_args,
+        })
+    }
+
+    // Finds the variant called `name` (case-insensitive) among the user-defined
+    // variants first and then CMake's build types.
+    pub fn resolve(name: &str, custom_variants: &[Variant]) -> Result<Variant, String> {
+        if let Some(variant) = custom_variants
+            .iter()
+            .find(|variant| variant.name.eq_ignore_ascii_case(name))
+        {
+            return Ok(variant.clone());
         }
+
+        if let Some(build_type) = BUILD_TYPES
+            .iter()
+            .find(|known| known.eq_ignore_ascii_case(name))
+        {
+            return Ok(Variant {
+                name: build_type.to_string(),
+                build_type: build_type.to_string(),
+                configure_args: IndexMap::new(),
+            });
+        }
+
+        let known: Vec<&str> = BUILD_TYPES
+            .iter()
+            .copied()
+            .chain(custom_variants.iter().map(|variant| variant.as_str()))
+            .collect();
+        Err(format!(
+            "unknown variant '{}', possible variants: {}",
+            name,
+            known.join(", ")
+        ))
+    }
+
+    // The variant name, used for the build/install directories.
+    pub fn as_str(&self) -> &str {
+        &self.name
+    }
+
+    // The CMake build type (CMAKE_BUILD_TYPE / --config) of the variant.
+    pub fn build_type(&self) -> &str {
+        &self.build_type
     }
 }
 
-fn parse_variant(arg: &str) -> Result<Variant, String> {
-    match arg.to_lowercase().as_str() {
-        "debug" => Ok(Variant::Debug),
-        "release" => Ok(Variant::Release),
-        "relWithDebInfo" => Ok(Variant::RelWithDebInfo),
-        _ => Err(format!("unknown variant '{arg}'")),
+// Parses a `NAME=VALUE` cache entry given with `-D`.
+fn parse_define(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_string(), value.to_string())),
+        _ => Err(format!("expected NAME=VALUE, got '{arg}'")),
     }
 }
 
+#[derive(Clone, Subcommand, Debug)]
+pub enum Command {
+    #[command(
+        about = "List the targets of the variant (Debug by default) with their type, source directory and artifacts. The variant is configured first if needed."
+    )]
+    Targets {
+        #[arg(long = "json", help = "Print the targets as JSON.")]
+        json: bool,
+    },
+    #[command(
+        about = "Build an executable target of the variant (Debug by default) and run it with the variant's install lib directory on LD_LIBRARY_PATH. Exits with the exit code of the program, or 128 + the signal number if it was killed by a signal."
+    )]
+    Run {
+        #[arg(help = "The executable target to build and run.")]
+        target: String,
+        #[arg(
+            last = true,
+            value_name = "ARGS",
+            help = "Arguments passed to the program, after `--`."
+        )]
+        args: Vec<String>,
+    },
+}
+
 #[derive(Clone, Parser, Debug)]
 #[command(
     name = "BuildMe",
@@ -60,29 +164,114 @@ pub struct CommandArgs {
     )]
     pub project_location: PathBuf,
 
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    // required unless a subcommand is given, see `AppContext::new`
     #[arg(
         long = "stages",
-        help = "Comma-separated list of stages to run",
+        help = "Comma-separated list of stages to run: clean, configure, build, install, test, package",
         value_parser = parse_stages,
     )]
-    pub stages: Box<[Stage]>,
+    pub stages: Option<Box<[Stage]>>,
 
     #[arg(
         long = "variant",
-        help ="possible variants: debug, release, relWithDebInfo",
-        value_parser = parse_variant,
+        help = "Comma-separated list of variants to build. possible variants: debug, release, relWithDebInfo, minSizeRel or a variant declared in `variants` of project_settings json (case-insensitive)"
+    )]
+    pub variant: Option<String>,
+
+    #[arg(
+        long = "parallel-variants",
+        help = "Build the variants concurrently, sharing the CPU cores between them. Each variant's output is redirected to its own build log."
     )]
-    pub variant: Variant,
+    pub parallel_variants: bool,
 
     #[arg(
         long = "generator",
-        help = "CMake generator to use. By defalut Ninja is used."
+        help = "CMake generator to use (e.g. \"Ninja\", \"Ninja Multi-Config\", \"Unix Makefiles\"). Overrides `generator` in project_settings json. By default Ninja is used."
     )]
     pub generator: Option<String>,
 
+    #[arg(
+        long = "target",
+        value_name = "TARGET",
+        help = "Build this target instead of the default `all`. Can be repeated. Overrides `default_targets` in project_settings json."
+    )]
+    pub targets: Vec<String>,
+
+    #[arg(
+        long = "auto-clean",
+        help = "Delete the variant build directory if it was configured with a different generator."
+    )]
+    pub auto_clean: bool,
+
+    #[arg(
+        long = "test-label",
+        help = "Only run the tests whose labels match this regular expression (ctest -L). Overrides `test.label` in project_settings json."
+    )]
+    pub test_label: Option<String>,
+
+    #[arg(
+        long = "test-regex",
+        help = "Only run the tests whose names match this regular expression (ctest -R). Overrides `test.regex` in project_settings json."
+    )]
+    pub test_regex: Option<String>,
+
     #[arg(
         long = "redirect-outstream",
         help = "Redirect output stream to a text file. Set this flag to redirect output stream to console instead."
     )]
     pub redirect_outstream: Option<bool>,
+
+    #[arg(
+        long = "log-tags",
+        help = "Prefix every line of the build log with the stream it came from ([out] or [err])."
+    )]
+    pub log_tags: bool,
+
+    #[arg(
+        long = "log-timestamps",
+        help = "Prefix every line of the build log with the time elapsed since its command started."
+    )]
+    pub log_timestamps: bool,
+
+    #[arg(
+        long = "tee",
+        help = "When redirecting the output stream, also print it to the console."
+    )]
+    pub tee: bool,
+
+    #[arg(
+        short = 'D',
+        value_name = "NAME=VALUE",
+        help = "Override a project option for this run, e.g. -D BUILD_TESTS=OFF. Can be repeated.",
+        value_parser = parse_define,
+    )]
+    pub defines: Vec<(String, String)>,
+
+    #[arg(
+        long = "unset",
+        value_name = "NAME",
+        help = "Don't pass the project option NAME to cmake for this run and remove it from the cache of the variant (cmake -U NAME). Can be repeated."
+    )]
+    pub unset: Vec<String>,
+
+    #[arg(
+        long = "save",
+        help = "Persist the -D and --unset overrides into project_settings json."
+    )]
+    pub save: bool,
+
+    #[arg(
+        long = "sync-cache",
+        help = "After configuring, add the cache entries the project defines that are missing from project_options to project_settings json without asking."
+    )]
+    pub sync_cache: bool,
+
+    #[arg(
+        long = "show-settings",
+        help = "Print every resolved setting with the layer it came from (user, project, local, environment or command line) and exit."
+    )]
+    pub show_settings: bool,
 }
@@ -0,0 +1,514 @@
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::build_error::*;
+use super::cmake_cache::*;
+
+// The CMake File API, see https://cmake.org/cmake/help/latest/manual/cmake-file-api.7.html
+// build-it writes a stateless query into the build directory before configuring and cmake
+// answers it with JSON reply files describing the targets, cache, toolchains and listfiles.
+
+const API_DIR: &str = ".cmake/api/v1";
+const CLIENT_NAME: &str = "client-build-it";
+const CODEMODEL_QUERY: &str = "codemodel-v2";
+const CACHE_QUERY: &str = "cache-v2";
+const TOOLCHAINS_QUERY: &str = "toolchains-v1";
+const CMAKE_FILES_QUERY: &str = "cmakeFiles-v1";
+const QUERIES: [&str; 4] = [
+    CODEMODEL_QUERY,
+    CACHE_QUERY,
+    TOOLCHAINS_QUERY,
+    CMAKE_FILES_QUERY,
+];
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum TargetKind {
+    Executable,
+    StaticLibrary,
+    SharedLibrary,
+    ModuleLibrary,
+    ObjectLibrary,
+    InterfaceLibrary,
+    Utility,
+}
+
+impl fmt::Display for TargetKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            TargetKind::Executable => "executable",
+            TargetKind::StaticLibrary => "static library",
+            TargetKind::SharedLibrary => "shared library",
+            TargetKind::ModuleLibrary => "module library",
+            TargetKind::ObjectLibrary => "object library",
+            TargetKind::InterfaceLibrary => "interface library",
+            TargetKind::Utility => "utility",
+        };
+        write!(f, "{}", kind)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TargetPaths {
+    pub source: PathBuf,
+    pub build: PathBuf,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Artifact {
+    pub path: PathBuf,
+}
+
+// A directory `install()` puts the target into, relative to the install prefix unless absolute.
+#[derive(Clone, Debug, Deserialize)]
+pub struct InstallDestination {
+    pub path: PathBuf,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct TargetInstall {
+    #[serde(default)]
+    pub destinations: Vec<InstallDestination>,
+}
+
+// A target of the code model. Its paths are made absolute when the reply is read.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Target {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: TargetKind,
+    pub paths: TargetPaths,
+    // the files the target produces, e.g. the executable or library
+    #[serde(default)]
+    pub artifacts: Vec<Artifact>,
+    // missing when the project doesn't install the target
+    #[serde(default)]
+    pub install: Option<TargetInstall>,
+}
+
+// The targets of one build configuration, there is one per type with multi-config generators.
+#[derive(Clone, Debug)]
+pub struct Configuration {
+    pub name: String,
+    pub targets: Vec<Target>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Compiler {
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    // e.g. "GNU", "Clang" or "MSVC"
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub version: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Toolchain {
+    pub language: String,
+    #[serde(default)]
+    pub compiler: Compiler,
+}
+
+// A listfile cmake read while configuring. Its path is made absolute when the reply is read.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CmakeFile {
+    pub path: PathBuf,
+    // written by the build system during configure
+    #[serde(default)]
+    pub is_generated: bool,
+    // outside of the source and build trees
+    #[serde(default)]
+    pub is_external: bool,
+    // part of the cmake installation
+    #[serde(default)]
+    pub is_cmake: bool,
+}
+
+// Everything cmake reported about a configured build directory. Only the code model is
+// required, older versions of cmake don't answer the other queries (toolchains-v1 needs
+// cmake 3.20) and leave them None.
+#[derive(Clone, Debug)]
+pub struct FileApiReply {
+    pub configurations: Vec<Configuration>,
+    pub cache: Option<Vec<CacheEntry>>,
+    pub toolchains: Option<Vec<Toolchain>>,
+    pub cmake_files: Option<Vec<CmakeFile>>,
+}
+
+impl CmakeFile {
+    // Whether the file belongs to the project rather than to cmake, the system or the build.
+    pub fn is_project_file(&self) -> bool {
+        !(self.is_generated || self.is_external || self.is_cmake)
+    }
+}
+
+impl FileApiReply {
+    // Returns the configuration called `name` (e.g. the build type), or the first one if there
+    // is none, as is the case for a single-config generator without CMAKE_BUILD_TYPE.
+    pub fn configuration(&self, name: &str) -> Option<&Configuration> {
+        self.configurations
+            .iter()
+            .find(|configuration| configuration.name.eq_ignore_ascii_case(name))
+            .or_else(|| self.configurations.first())
+    }
+}
+
+// The layouts of the reply files, only the members build-it uses are read.
+
+#[derive(Deserialize)]
+struct Index {
+    reply: Map<String, Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ReplyReference {
+    // missing when cmake couldn't answer the query, the reference holds an `error` instead
+    #[serde(default)]
+    json_file: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Paths {
+    source: PathBuf,
+    build: PathBuf,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TargetReference {
+    json_file: String,
+}
+
+#[derive(Deserialize)]
+struct ConfigurationObject {
+    name: String,
+    targets: Vec<TargetReference>,
+}
+
+#[derive(Deserialize)]
+struct CodemodelObject {
+    paths: Paths,
+    configurations: Vec<ConfigurationObject>,
+}
+
+#[derive(Deserialize)]
+struct CacheProperty {
+    name: String,
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct CacheEntryObject {
+    name: String,
+    value: String,
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    properties: Vec<CacheProperty>,
+}
+
+#[derive(Deserialize)]
+struct CacheObject {
+    entries: Vec<CacheEntryObject>,
+}
+
+#[derive(Deserialize)]
+struct ToolchainsObject {
+    toolchains: Vec<Toolchain>,
+}
+
+#[derive(Deserialize)]
+struct CmakeFilesObject {
+    inputs: Vec<CmakeFile>,
+}
+
+fn invalid_reply(path: &Path, message: impl fmt::Display) -> BuildError {
+    BuildError::Io(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), message),
+    ))
+}
+
+// Resolves a relative path of the reply against `base`, cmake writes `.` for `base` itself.
+fn resolve(base: &Path, path: &Path) -> PathBuf {
+    if path == Path::new(".") {
+        base.to_path_buf()
+    } else {
+        base.join(path)
+    }
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T, BuildError> {
+    let contents = fs::read_to_string(path)?;
+    serde_json::from_str(&contents).map_err(|e| invalid_reply(path, e))
+}
+
+// Reads the reply at `path`, if there is one.
+fn read_optional<T: DeserializeOwned>(path: Option<PathBuf>) -> Result<Option<T>, BuildError> {
+    path.map(|path| read_json(&path)).transpose()
+}
+
+// Asks cmake to answer the queries build-it needs the next time `build_dir` is configured.
+pub fn write_query(build_dir: &Path) -> std::io::Result<()> {
+    let query_dir = build_dir.join(API_DIR).join("query").join(CLIENT_NAME);
+    fs::create_dir_all(&query_dir)?;
+    for query in QUERIES {
+        let query_file = query_dir.join(query);
+        if !query_file.exists() {
+            fs::File::create(query_file)?;
+        }
+    }
+    Ok(())
+}
+
+// Reads the latest reply in `build_dir`. Returns None if cmake hasn't answered the code model
+// query yet, e.g. because the directory was configured by a cmake older than 3.14 or before
+// build-it wrote the query.
+pub fn read_reply(build_dir: &Path) -> Result<Option<FileApiReply>, BuildError> {
+    let reply_dir = build_dir.join(API_DIR).join("reply");
+    let Ok(entries) = fs::read_dir(&reply_dir) else {
+        return Ok(None);
+    };
+    // the index file names contain a timestamp, the latest one sorts last
+    let index_path = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("index-") && name.ends_with(".json"))
+        })
+        .max();
+    let Some(index_path) = index_path else {
+        return Ok(None);
+    };
+
+    let index: Index = read_json(&index_path)?;
+    let Some(Value::Object(client_reply)) = index.reply.get(CLIENT_NAME) else {
+        return Ok(None);
+    };
+    // returns the path of the reply to `query`, or None if cmake couldn't answer it
+    let reply_file = |query: &str| -> Result<Option<PathBuf>, BuildError> {
+        let reference: Option<ReplyReference> = client_reply
+            .get(query)
+            .cloned()
+            .map(serde_json::from_value)
+            .transpose()
+            .map_err(|e| invalid_reply(&index_path, e))?;
+        Ok(reference
+            .and_then(|reference| reference.json_file)
+            .map(|json_file| reply_dir.join(json_file)))
+    };
+
+    let Some(codemodel_file) = reply_file(CODEMODEL_QUERY)? else {
+        return Ok(None);
+    };
+    let codemodel: CodemodelObject = read_json(&codemodel_file)?;
+    let source_dir = codemodel.paths.source;
+    let build_dir = codemodel.paths.build;
+    let mut configurations = Vec::new();
+    for configuration in codemodel.configurations {
+        let mut targets = Vec::new();
+        for reference in &configuration.targets {
+            let mut target: Target = read_json(&reply_dir.join(&reference.json_file))?;
+            target.paths.source = resolve(&source_dir, &target.paths.source);
+            target.paths.build = resolve(&build_dir, &target.paths.build);
+            for artifact in &mut target.artifacts {
+                artifact.path = resolve(&build_dir, &artifact.path);
+            }
+            targets.push(target);
+        }
+        configurations.push(Configuration {
+            name: configuration.name,
+            targets,
+        });
+    }
+
+    let cache: Option<CacheObject> = read_optional(reply_file(CACHE_QUERY)?)?;
+    let cache = cache.map(|cache| {
+        cache
+            .entries
+            .into_iter()
+            .map(|entry| {
+                let property = |name: &str| {
+                    entry
+                        .properties
+                        .iter()
+                        .find(|property| property.name == name)
+                        .map(|property| property.value.clone())
+                };
+                CacheEntry {
+                    help: property("HELPSTRING").unwrap_or_default(),
+                    advanced: property("ADVANCED").as_deref() == Some("1"),
+                    name: entry.name,
+                    kind: entry.kind,
+                    value: entry.value,
+                }
+            })
+            .collect()
+    });
+
+    let toolchains: Option<ToolchainsObject> = read_optional(reply_file(TOOLCHAINS_QUERY)?)?;
+
+    let cmake_files: Option<CmakeFilesObject> = read_optional(reply_file(CMAKE_FILES_QUERY)?)?;
+    let cmake_files = cmake_files.map(|cmake_files| {
+        cmake_files
+            .inputs
+            .into_iter()
+            .map(|file| CmakeFile {
+                path: resolve(&source_dir, &file.path),
+                ..file
+            })
+            .collect()
+    });
+
+    Ok(Some(FileApiReply {
+        configurations,
+        cache,
+        toolchains: toolchains.map(|toolchains| toolchains.toolchains),
+        cmake_files,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes `files` into the reply directory of a new build directory and returns the latter.
+    fn build_dir_with_reply(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let build_dir =
+            std::env::temp_dir().join(format!("build-it-file-api-{}-{}", name, std::process::id()));
+        let reply_dir = build_dir.join(API_DIR).join("reply");
+        let _ = fs::remove_dir_all(&build_dir);
+        fs::create_dir_all(&reply_dir).unwrap();
+        for (file_name, contents) in files {
+            fs::write(reply_dir.join(file_name), contents).unwrap();
+        }
+        build_dir
+    }
+
+    const CODEMODEL: &str = r#"{
+  "kind": "codemodel",
+  "paths": { "source": "/work/project", "build": "/work/build/Debug" },
+  "configurations": [
+    { "name": "Debug", "targets": [ { "name": "app", "jsonFile": "target-app.json" } ] }
+  ]
+}"#;
+
+    const TARGET: &str = r#"{
+  "name": "app",
+  "type": "EXECUTABLE",
+  "paths": { "source": ".", "build": "." },
+  "artifacts": [ { "path": "bin/app" } ],
+  "install": {
+    "prefix": { "path": "/usr/local" },
+    "destinations": [ { "path": "libexec", "backtrace": 1 } ]
+  }
+}"#;
+
+    #[test]
+    fn reads_a_reply_without_toolchains() {
+        // cmake older than 3.20 doesn't know the toolchains query
+        let index = r#"{
+  "reply": {
+    "client-build-it": {
+      "codemodel-v2": { "kind": "codemodel", "jsonFile": "codemodel.json" },
+      "cache-v2": { "kind": "cache", "jsonFile": "cache.json" },
+      "toolchains-v1": { "error": "unknown request kind 'toolchains'" },
+      "cmakeFiles-v1": { "kind": "cmakeFiles", "jsonFile": "cmakeFiles.json" }
+    }
+  }
+}"#;
+        let build_dir = build_dir_with_reply(
+            "toolchains",
+            &[
+                ("index-2024-01-01T00-00-00-0000.json", index),
+                ("codemodel.json", CODEMODEL),
+                ("target-app.json", TARGET),
+                (
+                    "cache.json",
+                    r#"{ "entries": [ { "name": "FOO", "value": "ON", "type": "BOOL" } ] }"#,
+                ),
+                (
+                    "cmakeFiles.json",
+                    r#"{ "inputs": [ { "path": "CMakeLists.txt" } ] }"#,
+                ),
+            ],
+        );
+
+        let reply = read_reply(&build_dir).unwrap().unwrap();
+        fs::remove_dir_all(&build_dir).unwrap();
+
+        assert!(reply.toolchains.is_none());
+        assert_eq!(reply.cache.as_ref().map(Vec::len), Some(1));
+        let cmake_files = reply.cmake_files.as_ref().unwrap();
+        assert_eq!(
+            cmake_files[0].path,
+            PathBuf::from("/work/project/CMakeLists.txt")
+        );
+        let target = &reply.configuration("Debug").unwrap().targets[0];
+        assert_eq!(target.kind, TargetKind::Executable);
+        assert_eq!(target.paths.source, PathBuf::from("/work/project"));
+        assert_eq!(
+            target.artifacts[0].path,
+            PathBuf::from("/work/build/Debug/bin/app")
+        );
+        // install destinations stay relative to whichever prefix the variant is installed to
+        let install = target.install.as_ref().unwrap();
+        assert_eq!(install.destinations[0].path, PathBuf::from("libexec"));
+    }
+
+    #[test]
+    fn reads_a_reply_with_only_a_code_model() {
+        let index = r#"{
+  "reply": {
+    "client-build-it": {
+      "codemodel-v2": { "kind": "codemodel", "jsonFile": "codemodel.json" }
+    }
+  }
+}"#;
+        let build_dir = build_dir_with_reply(
+            "codemodel",
+            &[
+                ("index-2024-01-01T00-00-00-0000.json", index),
+                ("codemodel.json", CODEMODEL),
+                ("target-app.json", TARGET),
+            ],
+        );
+
+        let reply = read_reply(&build_dir).unwrap().unwrap();
+        fs::remove_dir_all(&build_dir).unwrap();
+
+        assert!(reply.cache.is_none());
+        assert!(reply.toolchains.is_none());
+        assert!(reply.cmake_files.is_none());
+        assert_eq!(reply.configurations.len(), 1);
+    }
+
+    #[test]
+    fn has_no_reply_without_a_code_model() {
+        let index = r#"{
+  "reply": {
+    "client-build-it": {
+      "codemodel-v2": { "error": "unknown request kind 'codemodel'" }
+    }
+  }
+}"#;
+        let build_dir =
+            build_dir_with_reply("none", &[("index-2024-01-01T00-00-00-0000.json", index)]);
+
+        let reply = read_reply(&build_dir).unwrap();
+        fs::remove_dir_all(&build_dir).unwrap();
+
+        assert!(reply.is_none());
+    }
+}
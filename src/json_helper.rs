@@ -1,70 +1,1390 @@
-use serde_json::Value;
+use colored::*;
+use indexmap::IndexMap;
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
 use serde_json::{json, to_writer_pretty};
-use std::collections::HashMap;
-use std::error::Error;
+use serde_json::{Map, Value};
+use std::env;
+use std::fmt;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
+use super::build_error::*;
+use super::command_args::*;
 use super::utility_helper::*;
 
 const PROJECT_SETTINGS_FILEPATH_NAME: &str = "project_settings.json";
+// untracked per-developer overrides of the project file
+const LOCAL_SETTINGS_FILEPATH_NAME: &str = "project_settings.local.json";
+// per-user settings, under the XDG config directory
+const USER_SETTINGS_DIR_NAME: &str = "build-it";
+const USER_SETTINGS_FILEPATH_NAME: &str = "settings.json";
+// environment variables holding settings
+const ENV_SETTINGS: &str = "BUILD_IT_SETTINGS";
+const ENV_WORKSPACE_DIR: &str = "BUILD_IT_WORKSPACE_DIR";
+const ENV_GENERATOR: &str = "BUILD_IT_GENERATOR";
+const VERSION: &str = "version";
+// Bump when the layout changes and add the matching step to MIGRATIONS.
+// Files written before versioning was introduced have no `version` and count as version 1.
+const SETTINGS_VERSION: u64 = 3;
 const WORKSPACE_DIR: &str = "workspace_dir";
 const PROJECT_OPTIONS: &str = "project_options";
 const EXTRA_ARGS: &str = "extra_args";
+const GENERATOR: &str = "generator";
+const DEFAULT_TARGETS: &str = "default_targets";
+const TEST: &str = "test";
+const PACKAGE: &str = "package";
+const INSTALL: &str = "install";
+const ARGUMENTS: &str = "arguments";
+const ARGUMENTS_CONFIGURE: &str = "configure";
+const ARGUMENTS_BUILD: &str = "build";
+const ARGUMENTS_NATIVE_TOOL: &str = "native_tool";
+const ARGUMENTS_INSTALL: &str = "install";
+const VARIANTS: &str = "variants";
+const VARIANT_BUILD_TYPE: &str = "build_type";
 
-type StringHashMap = HashMap<String, String>;
+// The top-level keys a settings file may contain.
+const TOP_LEVEL_KEYS: [&str; 11] = [
+    VERSION,
+    WORKSPACE_DIR,
+    PROJECT_OPTIONS,
+    EXTRA_ARGS,
+    GENERATOR,
+    DEFAULT_TARGETS,
+    TEST,
+    PACKAGE,
+    INSTALL,
+    ARGUMENTS,
+    VARIANTS,
+];
+
+// keeps the entries in the order they appear in the settings file
+type OrderedStringMap = IndexMap<String, String>;
+
+// The values read from a project settings JSON file
+pub struct ProjectSettings {
+    pub workspace_dir: PathBuf,
+    pub configure_args: OrderedStringMap,
+    pub extra_args: OrderedStringMap,
+    pub generator: Option<String>,
+    pub test_label: Option<String>,
+    pub test_regex: Option<String>,
+    pub package_generators: Vec<String>,
+    pub install_components: Vec<String>,
+    pub install_prefix: Option<PathBuf>,
+    pub install_strip: bool,
+    pub variants: Vec<Variant>,
+    pub arguments: ArgumentLists,
+    pub default_targets: Vec<String>,
+    // the final value of every setting and the layer it came from, keyed by path
+    // (e.g. "install.strip" or "project_options.-DBUILD_TESTS")
+    pub sources: IndexMap<String, (SettingsLayer, Value)>,
+}
+
+// The places settings are read from, from lowest to highest precedence.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SettingsLayer {
+    User,
+    Project,
+    Local,
+    Environment,
+    CommandLine,
+}
+
+impl fmt::Display for SettingsLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SettingsLayer::User => "user settings",
+            SettingsLayer::Project => "project settings",
+            SettingsLayer::Local => "local settings",
+            SettingsLayer::Environment => "environment",
+            SettingsLayer::CommandLine => "command line",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// The values of one settings layer.
+struct Layer {
+    kind: SettingsLayer,
+    // the file path or environment variable the values were read from
+    source: String,
+    // the raw text, to locate keys in error messages
+    contents: String,
+    values: Map<String, Value>,
+}
+
+impl Layer {
+    // Parses the text of a layer, rejecting syntax errors and unknown top-level keys.
+    fn parse(kind: SettingsLayer, source: String, contents: String) -> Result<Layer, BuildError> {
+        let invalid = |line: usize, column: usize, message: String| BuildError::SettingsInvalid {
+            message: format!("{}:{}:{}: {}", source, line, column, message),
+        };
+
+        let values = match serde_json::from_str::<Value>(&contents) {
+            Ok(Value::Object(values)) => values,
+            Ok(_) => return Err(invalid(1, 1, String::from("expected a JSON object"))),
+            Err(e) => return Err(invalid(e.line(), e.column(), strip_location(&e))),
+        };
+
+        // unknown top-level keys are checked before the types so typos get a suggestion
+        for key in values.keys() {
+            if !TOP_LEVEL_KEYS.contains(&key.as_str()) {
+                let (line, column) = find_key_location(&contents, key);
+                let mut message = format!("unknown key `{}`", key);
+                if let Some(suggestion) = suggest(key, &TOP_LEVEL_KEYS) {
+                    message.push_str(&format!(", did you mean `{}`?", suggestion));
+                }
+                return Err(invalid(line, column, message));
+            }
+        }
+
+        Ok(Layer {
+            kind,
+            source,
+            contents,
+            values,
+        })
+    }
+
+    // Reads a layer from a settings file, or None if the file doesn't exist.
+    fn from_file(kind: SettingsLayer, file_path: &Path) -> Result<Option<Layer>, BuildError> {
+        if !file_path.exists() {
+            return Ok(None);
+        }
+        let contents = fs::read_to_string(file_path)?;
+        Layer::parse(kind, file_path.display().to_string(), contents).map(Some)
+    }
+
+    // Reads the layer set through environment variables: a JSON object in BUILD_IT_SETTINGS,
+    // and shortcuts for the most common keys.
+    fn from_environment() -> Result<Option<Layer>, BuildError> {
+        let mut layer = match env::var(ENV_SETTINGS) {
+            Ok(contents) => Layer::parse(
+                SettingsLayer::Environment,
+                ENV_SETTINGS.to_string(),
+                contents,
+            )?,
+            Err(_) => Layer {
+                kind: SettingsLayer::Environment,
+                source: String::from("environment"),
+                contents: String::new(),
+                values: Map::new(),
+            },
+        };
+
+        for (variable, key) in [
+            (ENV_WORKSPACE_DIR, WORKSPACE_DIR),
+            (ENV_GENERATOR, GENERATOR),
+        ] {
+            if let Ok(value) = env::var(variable) {
+                layer.values.insert(key.to_string(), Value::String(value));
+            }
+        }
+
+        if layer.values.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(layer))
+    }
+
+    // Describes where `key_path` is defined in this layer, as "file:line:column" for files.
+    fn locate(&self, key_path: &str) -> String {
+        match self.kind {
+            SettingsLayer::User | SettingsLayer::Project | SettingsLayer::Local => {
+                let (line, column) = find_key_location(&self.contents, key_path);
+                format!("{}:{}:{}", self.source, line, column)
+            }
+            SettingsLayer::Environment | SettingsLayer::CommandLine => self.source.clone(),
+        }
+    }
+}
+
+// Returns the path of the user settings file, `$XDG_CONFIG_HOME/build-it/settings.json`
+// or `~/.config/build-it/settings.json`.
+fn user_settings_filepath() -> Option<PathBuf> {
+    let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(
+        config_dir
+            .join(USER_SETTINGS_DIR_NAME)
+            .join(USER_SETTINGS_FILEPATH_NAME),
+    )
+}
+
+// Merges the values of a layer into `target`, recording the layer of every leaf value in `sources`.
+fn merge_layer(
+    target: &mut Map<String, Value>,
+    values: &Map<String, Value>,
+    prefix: &str,
+    kind: SettingsLayer,
+    sources: &mut IndexMap<String, (SettingsLayer, Value)>,
+) {
+    for (key, value) in values {
+        let path = if prefix.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+
+        if let (Some(Value::Object(target)), Value::Object(values)) = (target.get_mut(key), value) {
+            merge_layer(target, values, &path, kind, sources);
+            continue;
+        }
+
+        let nested = format!("{}.", path);
+        sources.retain(|source, _| *source != path && !source.starts_with(&nested));
+        record_sources(value, &path, kind, sources);
+        target.insert(key.to_string(), value.clone());
+    }
+}
+
+fn record_sources(
+    value: &Value,
+    path: &str,
+    kind: SettingsLayer,
+    sources: &mut IndexMap<String, (SettingsLayer, Value)>,
+) {
+    match value {
+        Value::Object(values) if !values.is_empty() => {
+            for (key, value) in values {
+                record_sources(value, &format!("{}.{}", path, key), kind, sources);
+            }
+        }
+        _ => {
+            sources.insert(path.to_string(), (kind, value.clone()));
+        }
+    }
+}
+
+// Returns the `project_options` key of a cmake cache entry, e.g. "-DBUILD_TESTS".
+pub fn option_key(name: &str) -> String {
+    format!("-D{}", name)
+}
+
+// Sets the cache entry `name` (`NAME` or `NAME:TYPE`) to `value` in `project_options`, replacing
+// the keys that set it with another type or without one.
+pub fn insert_option(project_options: &mut Map<String, Value>, name: &str, value: &str) {
+    let key = option_key(name);
+    let entry = name.split_once(':').map_or(name, |(entry, _)| entry);
+    project_options.retain(|other, _| *other == key || !is_option_key(other, entry));
+    project_options.insert(key, json!(value));
+}
+
+// Whether the `project_options` key `key` sets the cache entry `name`, with or without a type
+// (`-DNAME` or `-DNAME:BOOL`).
+pub fn is_option_key(key: &str, name: &str) -> bool {
+    match key.strip_prefix("-D") {
+        Some(entry) => entry == name || entry.split_once(':').map(|(entry, _)| entry) == Some(name),
+        None => false,
+    }
+}
+
+// Verbatim arguments appended to each cmake invocation, in file order.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ArgumentLists {
+    // `cmake` when configuring, e.g. "--trace-expand", "-Wno-dev"
+    pub configure: Vec<String>,
+    // `cmake --build`, e.g. "--verbose"
+    pub build: Vec<String>,
+    // the native build tool, after `--`, e.g. "-k", "0"
+    pub native_tool: Vec<String>,
+    // `cmake --install`
+    pub install: Vec<String>,
+}
+
+// The value of a cmake option. Booleans and numbers are accepted as well as strings,
+// `true`/`false` become "ON"/"OFF".
+#[derive(Debug)]
+struct OptionValue(String);
+
+impl<'de> Deserialize<'de> for OptionValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match Value::deserialize(deserializer)? {
+            Value::String(value) => Ok(OptionValue(value)),
+            Value::Bool(value) => Ok(OptionValue(if value { "ON" } else { "OFF" }.to_string())),
+            Value::Number(value) => Ok(OptionValue(value.to_string())),
+            other => Err(de::Error::invalid_type(
+                unexpected(&other),
+                &"a string, a boolean or a number",
+            )),
+        }
+    }
+}
+
+fn unexpected(value: &Value) -> de::Unexpected<'_> {
+    match value {
+        Value::Null => de::Unexpected::Unit,
+        Value::Array(_) => de::Unexpected::Seq,
+        Value::Object(_) => de::Unexpected::Map,
+        Value::Bool(value) => de::Unexpected::Bool(*value),
+        Value::String(value) => de::Unexpected::Str(value),
+        Value::Number(_) => de::Unexpected::Other("number"),
+    }
+}
+
+fn to_ordered_strings(values: IndexMap<String, OptionValue>) -> OrderedStringMap {
+    values
+        .into_iter()
+        .map(|(key, value)| (key, value.0))
+        .collect()
+}
+
+// Treats an empty string as unset.
+fn non_empty(value: Option<String>) -> Option<String> {
+    value.filter(|value| !value.is_empty())
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TestSettings {
+    label: Option<String>,
+    regex: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct PackageSettings {
+    generators: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct InstallSettings {
+    components: Vec<String>,
+    prefix: Option<String>,
+    strip: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VariantSettings {
+    build_type: String,
+    #[serde(default)]
+    configure_args: IndexMap<String, OptionValue>,
+}
+
+// The layout of project_settings.json. Only `workspace_dir` is required,
+// older settings files don't have the other keys.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SettingsFile {
+    // checked by `migrate_project_setting` before the file is parsed
+    #[serde(default, rename = "version")]
+    _version: u64,
+    workspace_dir: String,
+    #[serde(default)]
+    project_options: IndexMap<String, OptionValue>,
+    #[serde(default)]
+    extra_args: IndexMap<String, OptionValue>,
+    #[serde(default)]
+    generator: Option<String>,
+    #[serde(default)]
+    default_targets: Vec<String>,
+    #[serde(default)]
+    test: TestSettings,
+    #[serde(default)]
+    package: PackageSettings,
+    #[serde(default)]
+    install: InstallSettings,
+    #[serde(default)]
+    arguments: ArgumentLists,
+    #[serde(default)]
+    variants: IndexMap<String, VariantSettings>,
+}
+
+impl SettingsFile {
+    // Checks the values the types alone can't, and converts the file into ProjectSettings.
+    // Errors are returned as (key path, message).
+    fn validate(self) -> Result<ProjectSettings, (String, String)> {
+        for (key, arguments) in [
+            (ARGUMENTS_CONFIGURE, &self.arguments.configure),
+            (ARGUMENTS_BUILD, &self.arguments.build),
+            (ARGUMENTS_NATIVE_TOOL, &self.arguments.native_tool),
+            (ARGUMENTS_INSTALL, &self.arguments.install),
+        ] {
+            if arguments.iter().any(|argument| argument.trim().is_empty()) {
+                return Err((
+                    format!("{}.{}", ARGUMENTS, key),
+                    "must not contain empty arguments".to_string(),
+                ));
+            }
+        }
+        // the arguments for the native build tool have their own list
+        if self.arguments.build.iter().any(|argument| argument == "--") {
+            return Err((
+                format!("{}.{}", ARGUMENTS, ARGUMENTS_BUILD),
+                format!(
+                    "must not contain `--`, put the native build tool arguments in `{}.{}`",
+                    ARGUMENTS, ARGUMENTS_NATIVE_TOOL
+                ),
+            ));
+        }
+
+        if self.workspace_dir.trim().is_empty() {
+            return Err((WORKSPACE_DIR.to_string(), "must not be empty".to_string()));
+        }
+
+        if self
+            .default_targets
+            .iter()
+            .any(|target| target.trim().is_empty())
+        {
+            return Err((
+                DEFAULT_TARGETS.to_string(),
+                "must not contain empty target names".to_string(),
+            ));
+        }
+
+        let mut variants = Vec::new();
+        for (name, variant) in self.variants {
+            Variant::check_name(&name)
+                .map_err(|message| (format!("{}.{}", VARIANTS, name), message))?;
+            let variant = Variant::custom(
+                &name,
+                &variant.build_type,
+                to_ordered_strings(variant.configure_args),
+            )
+            .map_err(|message| {
+                (
+                    format!("{}.{}.{}", VARIANTS, name, VARIANT_BUILD_TYPE),
+                    message,
+                )
+            })?;
+            variants.push(variant);
+        }
+
+        Ok(ProjectSettings {
+            workspace_dir: PathBuf::from(self.workspace_dir),
+            configure_args: to_ordered_strings(self.project_options),
+            extra_args: to_ordered_strings(self.extra_args),
+            generator: non_empty(self.generator),
+            test_label: non_empty(self.test.label),
+            test_regex: non_empty(self.test.regex),
+            package_generators: self.package.generators,
+            install_components: self.install.components,
+            install_prefix: non_empty(self.install.prefix).map(PathBuf::from),
+            install_strip: self.install.strip,
+            variants,
+            arguments: self.arguments,
+            default_targets: self.default_targets,
+            sources: IndexMap::new(),
+        })
+    }
+}
+
+// Returns a new settings file of the current version. The other keys are left out so that
+// their defaults, and the user settings, apply until the project sets them.
+fn default_settings(workspace_dir: &str, project_options: Value) -> Value {
+    json!({
+        VERSION: SETTINGS_VERSION,
+        WORKSPACE_DIR: workspace_dir,
+        PROJECT_OPTIONS: project_options,
+        EXTRA_ARGS: {},
+    })
+}
+
+// A migration step upgrades the settings from one version to the next and
+// returns a description of every change it made.
+type Migration = fn(&mut Map<String, Value>) -> Vec<String>;
+
+// MIGRATIONS[i] upgrades a version i + 1 file to version i + 2.
+const MIGRATIONS: [Migration; 2] = [migrate_v1_to_v2, migrate_v2_to_v3];
+
+// Version 2 added `generator`, `test`, `package`, `install`, `arguments` and `variants`.
+// They are optional, a missing key keeps its default.
+fn migrate_v1_to_v2(_settings: &mut Map<String, Value>) -> Vec<String> {
+    Vec::new()
+}
+
+// Version 3 added the optional `default_targets`.
+fn migrate_v2_to_v3(_settings: &mut Map<String, Value>) -> Vec<String> {
+    Vec::new()
+}
+
+// serde_json appends " at line X column Y" to its messages, the location is reported separately.
+fn strip_location(e: &serde_json::Error) -> String {
+    let message = e.to_string();
+    match message.rsplit_once(" at line ") {
+        Some((message, _)) => message.to_string(),
+        None => message,
+    }
+}
+
+// Records the 1-based line and column of every key of a JSON text by its path, e.g.
+// `variants.asan.build_type`, and of every array element by its index, e.g.
+// `arguments.configure[1]`. The text is expected to be valid JSON.
+fn key_locations(contents: &str) -> Vec<(String, (usize, usize))> {
+    let mut locations = Vec::new();
+    // the path of every open object or array, with the index of the next element of an array
+    let mut containers: Vec<(String, Option<usize>)> = Vec::new();
+    // the path of the value being read, set by its key or its array index
+    let mut value_path: Option<String> = None;
+    let mut expecting_key = false;
+    let (mut line, mut column) = (1, 0);
+    let mut chars = contents.chars();
+    while let Some(c) = chars.next() {
+        column += 1;
+        if c == '\n' {
+            line += 1;
+            column = 0;
+            continue;
+        }
+        if c.is_whitespace() {
+            continue;
+        }
+        let position = (line, column);
+
+        if let Some((path, Some(index))) = containers.last() {
+            if value_path.is_none() && c != ',' && c != ']' {
+                let element_path = format!("{}[{}]", path, index);
+                locations.push((element_path.clone(), position));
+                value_path = Some(element_path);
+            }
+        }
+
+        match c {
+            '"' => {
+                let mut text = String::new();
+                while let Some(c) = chars.next() {
+                    column += 1;
+                    match c {
+                        '\\' => {
+                            if let Some(escaped) = chars.next() {
+                                column += 1;
+                                text.push(escaped);
+                            }
+                        }
+                        '"' => break,
+                        _ => text.push(c),
+                    }
+                }
+                if expecting_key {
+                    let parent = containers.last().map_or("", |(path, _)| path.as_str());
+                    let key_path = if parent.is_empty() {
+                        text
+                    } else {
+                        format!("{}.{}", parent, text)
+                    };
+                    locations.push((key_path.clone(), position));
+                    value_path = Some(key_path);
+                    expecting_key = false;
+                } else {
+                    value_path = None;
+                }
+            }
+            '{' => {
+                containers.push((value_path.take().unwrap_or_default(), None));
+                expecting_key = true;
+            }
+            '[' => containers.push((value_path.take().unwrap_or_default(), Some(0))),
+            '}' | ']' => {
+                containers.pop();
+                value_path = None;
+            }
+            ',' => {
+                match containers.last_mut() {
+                    Some((_, Some(index))) => *index += 1,
+                    _ => expecting_key = true,
+                }
+                value_path = None;
+            }
+            _ => {}
+        }
+    }
+    locations
+}
+
+// Finds the 1-based line and column of the key at `key_path` (see `key_locations`), or of its
+// closest enclosing key if it isn't in the file. A repeated key is located at its last
+// occurrence, the one serde keeps.
+fn find_key_location(contents: &str, key_path: &str) -> (usize, usize) {
+    let locations = key_locations(contents);
+    let mut key_path = key_path;
+    loop {
+        if let Some((_, position)) = locations.iter().rev().find(|(path, _)| path == key_path) {
+            return *position;
+        }
+        match key_path.rfind(['.', '[']) {
+            Some(end) => key_path = &key_path[..end],
+            None => return (1, 1),
+        }
+    }
+}
+
+// Reads the `version` of a settings file, a file written before it existed is version 1.
+fn settings_version(settings: &Map<String, Value>) -> Result<u64, String> {
+    match settings.get(VERSION) {
+        None => Ok(1),
+        Some(version) => version
+            .as_u64()
+            .filter(|version| *version > 0)
+            .ok_or_else(|| format!("`{}` must be a positive integer", VERSION)),
+    }
+}
+
+// Upgrades `settings` from `version` to `SETTINGS_VERSION`, keeping the order of the keys with
+// `version` first. Returns the upgraded settings and a description of every change.
+fn migrate_settings(
+    mut settings: Map<String, Value>,
+    version: u64,
+) -> (Map<String, Value>, Vec<String>) {
+    let mut changes = Vec::new();
+    for migration in &MIGRATIONS[(version - 1) as usize..] {
+        changes.extend(migration(&mut settings));
+    }
+
+    let mut migrated = Map::new();
+    migrated.insert(VERSION.to_string(), json!(SETTINGS_VERSION));
+    // a plain `remove` would move the last key into the place of `version`
+    settings.shift_remove(VERSION);
+    migrated.extend(settings);
+    (migrated, changes)
+}
+
+// Returns the closest of `candidates` to `key`, if any is close enough to be a likely typo.
+pub fn suggest<'a>(key: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    candidates
+        .iter()
+        .map(|candidate| (strsim::jaro_winkler(key, candidate), *candidate))
+        .filter(|(score, _)| *score > 0.8)
+        .max_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|(_, candidate)| candidate)
+}
+
+// Variables that only have a value once the variant being built is known. They are left
+// in place when the settings are loaded and expanded by `expand_variant_variables`.
+const VARIABLE_VARIANT: &str = "variant";
+const VARIABLE_BUILD_DIR: &str = "build_dir";
+const VARIABLE_PROJECT_DIR: &str = "project_dir";
+const VARIABLE_WORKSPACE_DIR: &str = "workspace_dir";
+const VARIABLE_ENV_PREFIX: &str = "env:";
+
+// Replaces every `${name}` in `input` with `lookup(name)`. `$${` is an escaped `${`.
+// With `defer` set, the variant variables and escapes are kept as written so a later
+// pass can expand them; otherwise escapes are unescaped and any unknown variable is an error.
+fn interpolate<F>(input: &str, lookup: F, defer: bool) -> Result<String, String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut output = String::new();
+    let mut rest = input;
+    while let Some(start) = rest.find('$') {
+        output.push_str(&rest[..start]);
+        let remaining = &rest[start..];
+        if let Some(after) = remaining.strip_prefix("$${") {
+            output.push_str(if defer { "$${" } else { "${" });
+            rest = after;
+        } else if let Some(after) = remaining.strip_prefix("${") {
+            let end = after.find('}').ok_or_else(|| {
+                format!(
+                    "unterminated variable in \"{}\", write `$${{` for a literal `${{`",
+                    input
+                )
+            })?;
+            let name = &after[..end];
+            match lookup(name) {
+                // keep a `${` in the value itself from being expanded by the later pass
+                Some(value) if defer => output.push_str(&value.replace("${", "$${")),
+                Some(value) => output.push_str(&value),
+                None if defer && [VARIABLE_VARIANT, VARIABLE_BUILD_DIR].contains(&name) => {
+                    output.push_str(&remaining[..end + 3])
+                }
+                None => return Err(describe_undefined(name)),
+            }
+            rest = &after[end + 1..];
+        } else {
+            output.push('$');
+            rest = &remaining[1..];
+        }
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+fn describe_undefined(name: &str) -> String {
+    match name.strip_prefix(VARIABLE_ENV_PREFIX) {
+        Some(variable) => format!("environment variable `{}` is not set", variable),
+        // `workspace_dir` can't refer to itself or to a variant
+        None if [
+            VARIABLE_PROJECT_DIR,
+            VARIABLE_WORKSPACE_DIR,
+            VARIABLE_VARIANT,
+            VARIABLE_BUILD_DIR,
+        ]
+        .contains(&name) =>
+        {
+            format!("`${{{}}}` can't be used in this setting", name)
+        }
+        None => format!(
+            "undefined variable `${{{}}}`, expected one of: env:NAME, {}, {}, {}, {}",
+            name,
+            VARIABLE_PROJECT_DIR,
+            VARIABLE_WORKSPACE_DIR,
+            VARIABLE_VARIANT,
+            VARIABLE_BUILD_DIR
+        ),
+    }
+}
+
+// Looks up `${env:NAME}` and the variables that are known before a variant is chosen.
+fn lookup_variable(name: &str, project_dir: &Path, workspace_dir: Option<&Path>) -> Option<String> {
+    if let Some(variable) = name.strip_prefix(VARIABLE_ENV_PREFIX) {
+        return env::var(variable).ok();
+    }
+    match name {
+        VARIABLE_PROJECT_DIR => Some(project_dir.display().to_string()),
+        VARIABLE_WORKSPACE_DIR => workspace_dir.map(|dir| dir.display().to_string()),
+        _ => None,
+    }
+}
+
+// Interpolates every value of `values`, `key` is the key path used to report errors.
+fn interpolate_values<F>(
+    values: &mut OrderedStringMap,
+    key: &str,
+    lookup: F,
+    defer: bool,
+) -> Result<(), (String, String)>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    for (name, value) in values.iter_mut() {
+        *value = interpolate(value, &lookup, defer)
+            .map_err(|message| (format!("{}.{}", key, name), message))?;
+    }
+    Ok(())
+}
 
 // A struct with utility functions to create and parse project settings in JSON format
 pub struct JsonUtil {}
 
 impl JsonUtil {
-    // Function to create project settings in JSON format at a given directory path
-    pub fn create_project_setting(dir_path: &PathBuf) -> Result<PathBuf, Box<dyn Error>> {
+    // Function to create project settings in JSON format at a given directory path.
+    // The discovered options are printed on stderr with `status_to_stderr`.
+    pub fn create_project_setting(
+        dir_path: &Path,
+        status_to_stderr: bool,
+    ) -> Result<PathBuf, BuildError> {
         let project_settings_filepath = dir_path.join(PROJECT_SETTINGS_FILEPATH_NAME);
         if project_settings_filepath.exists() {
             return Ok(project_settings_filepath);
         }
         // set the data to write to the JSON file
-        let workspace_dir = "path/to/valid/directory";
-        let extra_args = json!({});
+        let workspace_dir = Self::choose_workspace_dir(dir_path, status_to_stderr)?;
+        let options = UtilityHelper::fetch_cmake_project_options(dir_path)?;
+        if !options.is_empty() {
+            UtilityHelper::print_status(
+                "Discovered project options".blue().bold(),
+                status_to_stderr,
+            );
+        }
+        for option in options.values() {
+            let file = option.file.strip_prefix(dir_path).unwrap_or(&option.file);
+            let line = format!(
+                "\t{}={}  {}",
+                option_key(&option.name).cyan(),
+                option.value,
+                format!("({}:{})", file.display(), option.line).dimmed()
+            );
+            UtilityHelper::print_status(line.normal(), status_to_stderr);
+        }
+        let project_options: Map<String, Value> = options
+            .into_values()
+            .map(|option| (option_key(&option.name), json!(option.value)))
+            .collect();
+        let project_options = Value::Object(project_options);
+        let data = default_settings(&workspace_dir, project_options);
+
+        // create a new file with project settings data and write it in JSON format
+        let j_file = UtilityHelper::create_new_file(&project_settings_filepath)?;
+        to_writer_pretty(&j_file, &data)?;
+
+        // only now, a failed discovery leaves nothing behind. Variables are only expanded
+        // when the settings are loaded, leave those directories to the user
+        if !workspace_dir.contains("${") {
+            UtilityHelper::create_new_directory(&dir_path.join(&workspace_dir))?;
+        }
 
-        let data = json!({
-            WORKSPACE_DIR: workspace_dir,
-            PROJECT_OPTIONS: UtilityHelper::fetch_cmake_project_options(),
-            EXTRA_ARGS: extra_args,
+        Ok(project_settings_filepath)
+    }
+
+    // Returns the workspace directory for a new settings file: a `<project>-workspace`
+    // directory next to the project, or whatever the user enters when asked on a terminal.
+    fn choose_workspace_dir(dir_path: &Path, status_to_stderr: bool) -> Result<String, BuildError> {
+        let project_dir_name = dir_path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| String::from("project"));
+        let default_workspace_dir = format!("../{}-workspace", project_dir_name);
+
+        let answer = UtilityHelper::prompt(
+            &format!(
+                "Workspace directory for the build and install trees, relative to {} [{}]: ",
+                dir_path.display(),
+                default_workspace_dir
+            ),
+            status_to_stderr,
+        )?;
+        match answer {
+            Some(answer) if !answer.is_empty() => Ok(answer),
+            _ => Ok(default_workspace_dir),
+        }
+    }
+
+    // Upgrades a settings file written by an older build-it to the current version.
+    // The original file is kept next to it as `project_settings.json.v<version>.bak`
+    // and every change is printed, on stderr with `status_to_stderr`. Returns whether the
+    // file was migrated.
+    pub fn migrate_project_setting(
+        file_path: &Path,
+        status_to_stderr: bool,
+    ) -> Result<bool, BuildError> {
+        let json_file = fs::read_to_string(file_path)?;
+        // a malformed file is left for `parse_json` to report
+        let settings = match serde_json::from_str::<Value>(&json_file) {
+            Ok(Value::Object(settings)) => settings,
+            _ => return Ok(false),
+        };
+
+        let version =
+            settings_version(&settings).map_err(|message| BuildError::SettingsInvalid {
+                message: format!("{}: {}", file_path.display(), message),
+            })?;
+
+        if version > SETTINGS_VERSION {
+            return Err(BuildError::SettingsInvalid {
+                message: format!(
+                    "{} is version {} but this build-it only understands up to version {}, please update build-it",
+                    file_path.display(),
+                    version,
+                    SETTINGS_VERSION
+                ),
+            });
+        }
+        if version == SETTINGS_VERSION {
+            return Ok(false);
+        }
+
+        let (migrated, changes) = migrate_settings(settings, version);
+
+        let mut backup_name = file_path.as_os_str().to_owned();
+        backup_name.push(format!(".v{}.bak", version));
+        let backup_path = PathBuf::from(backup_name);
+        fs::copy(file_path, &backup_path)?;
+
+        let j_file = UtilityHelper::create_new_file(&file_path.to_path_buf())?;
+        to_writer_pretty(&j_file, &Value::Object(migrated))?;
+
+        let msg = format!(
+            "Migrated {} from version {} to version {} (backup: {})",
+            file_path.display(),
+            version,
+            SETTINGS_VERSION,
+            backup_path.display()
+        );
+        UtilityHelper::print_status(msg.yellow().bold(), status_to_stderr);
+        for change in changes {
+            UtilityHelper::print_status(format!("\t{}", change).yellow(), status_to_stderr);
+        }
+
+        Ok(true)
+    }
+
+    // Writes `-D` overrides into `project_options` of the project settings file and removes
+    // the unset options from it, keeping the order of the other keys.
+    pub fn save_project_options(
+        file_path: &Path,
+        defines: &[(String, String)],
+        unset: &[String],
+        status_to_stderr: bool,
+    ) -> Result<(), BuildError> {
+        let json_file = fs::read_to_string(file_path)?;
+        let mut settings: Value = serde_json::from_str(&json_file)?;
+        let project_options = settings
+            .as_object_mut()
+            .map(|settings| settings.entry(PROJECT_OPTIONS).or_insert_with(|| json!({})))
+            .and_then(|project_options| project_options.as_object_mut())
+            .ok_or_else(|| BuildError::SettingsInvalid {
+                message: format!(
+                    "{}: `{}` must be an object",
+                    file_path.display(),
+                    PROJECT_OPTIONS
+                ),
+            })?;
+
+        // in the order of the run, an option both defined and unset ends up unset
+        for (name, value) in defines {
+            insert_option(project_options, name, value);
+        }
+        for name in unset {
+            project_options.retain(|key, _| !is_option_key(key, name));
+        }
+
+        let j_file = UtilityHelper::create_new_file(&file_path.to_path_buf())?;
+        to_writer_pretty(&j_file, &settings)?;
+
+        UtilityHelper::print_status(
+            format!("Saved project options to {}", file_path.display())
+                .yellow()
+                .bold(),
+            status_to_stderr,
+        );
+        Ok(())
+    }
+
+    // Loads the project settings by merging, in order of increasing precedence, the user
+    // settings, the project file, its untracked local overrides, the environment and the
+    // command line. Objects are merged key by key, any other value replaces the previous one.
+    // Every problem is reported with the location and key path of the offending value.
+    pub fn load_settings(
+        project_dir: &Path,
+        command_line: Map<String, Value>,
+    ) -> Result<ProjectSettings, BuildError> {
+        let mut layers = Vec::new();
+        if let Some(user_settings_filepath) = user_settings_filepath() {
+            layers.extend(Layer::from_file(
+                SettingsLayer::User,
+                &user_settings_filepath,
+            )?);
+        }
+        let project_settings_filepath = project_dir.join(PROJECT_SETTINGS_FILEPATH_NAME);
+        layers.extend(Layer::from_file(
+            SettingsLayer::Project,
+            &project_settings_filepath,
+        )?);
+        layers.extend(Layer::from_file(
+            SettingsLayer::Local,
+            &project_dir.join(LOCAL_SETTINGS_FILEPATH_NAME),
+        )?);
+        layers.extend(Layer::from_environment()?);
+        layers.push(Layer {
+            kind: SettingsLayer::CommandLine,
+            source: String::from("command line"),
+            contents: String::new(),
+            values: command_line,
         });
 
-        // Use a closure to create a new file with project settings data and write it in JSON format
-        let file_path = UtilityHelper::current_working_directory(&dir_path, || {
-            let j_file = UtilityHelper::create_new_file(&project_settings_filepath)?;
-            to_writer_pretty(&j_file, &data)?;
-            Ok(project_settings_filepath)
+        let mut merged = Map::new();
+        let mut sources = IndexMap::new();
+        for layer in &layers {
+            merge_layer(&mut merged, &layer.values, "", layer.kind, &mut sources);
+        }
+
+        // reports an error at the layer that supplied `key_path`
+        let invalid = |key_path: &str, message: String| {
+            let kind = sources
+                .iter()
+                .find(|(path, _)| {
+                    *path == key_path
+                        || path.starts_with(&format!("{}.", key_path))
+                        // an element of an array, the arrays are merged as a whole
+                        || key_path.starts_with(&format!("{}[", path))
+                })
+                .map(|(_, (kind, _))| *kind)
+                .unwrap_or(SettingsLayer::Project);
+            let location = match layers.iter().rev().find(|layer| layer.kind == kind) {
+                Some(layer) => layer.locate(key_path),
+                None => project_settings_filepath.display().to_string(),
+            };
+            let message = if key_path.is_empty() || key_path == "." {
+                message
+            } else {
+                format!("`{}`: {}", key_path, message)
+            };
+            BuildError::SettingsInvalid {
+                message: format!("{}: {}", location, message),
+            }
+        };
+
+        let settings: SettingsFile = serde_path_to_error::deserialize(Value::Object(merged))
+            .map_err(|e| {
+                let path = e.path().to_string();
+                invalid(&path, strip_location(e.inner()))
+            })?;
+
+        let mut settings = settings
+            .validate()
+            .map_err(|(key_path, message)| invalid(&key_path, message))?;
+        Self::interpolate_settings(&mut settings, project_dir)
+            .map_err(|(key_path, message)| invalid(&key_path, message))?;
+        settings.sources = sources;
+        Ok(settings)
+    }
+
+    // Expands the variables in `workspace_dir`, `project_options`, `extra_args` and the
+    // variants' `configure_args`. `workspace_dir` is made absolute first so the other values
+    // can refer to it, the variant variables are left for `expand_variant_variables`.
+    fn interpolate_settings(
+        settings: &mut ProjectSettings,
+        project_dir: &Path,
+    ) -> Result<(), (String, String)> {
+        let workspace_dir = interpolate(
+            &settings.workspace_dir.to_string_lossy(),
+            |name| lookup_variable(name, project_dir, None),
+            false,
+        )
+        .map_err(|message| (WORKSPACE_DIR.to_string(), message))?;
+        // a relative workspace_dir is relative to the project location
+        settings.workspace_dir = project_dir.join(workspace_dir);
+
+        let workspace_dir = settings.workspace_dir.clone();
+        let lookup = |name: &str| lookup_variable(name, project_dir, Some(&workspace_dir));
+        interpolate_values(&mut settings.configure_args, PROJECT_OPTIONS, lookup, true)?;
+        interpolate_values(&mut settings.extra_args, EXTRA_ARGS, lookup, true)?;
+        for variant in &mut settings.variants {
+            let key = format!("{}.{}.configure_args", VARIANTS, variant.as_str());
+            interpolate_values(&mut variant.configure_args, &key, lookup, true)?;
+        }
+        Ok(())
+    }
+
+    // Expands `${variant}` and `${build_dir}` in option values returned by `load_settings`,
+    // and turns the remaining `$${` escapes into `${`. `key` names the settings section
+    // the values came from.
+    pub fn expand_variant_variables(
+        values: &IndexMap<String, String>,
+        key: &str,
+        variant: &Variant,
+        build_dir: &Path,
+    ) -> Result<IndexMap<String, String>, BuildError> {
+        let lookup = |name: &str| match name {
+            VARIABLE_VARIANT => Some(variant.as_str().to_string()),
+            VARIABLE_BUILD_DIR => Some(build_dir.display().to_string()),
+            _ => None,
+        };
+        let mut values = values.clone();
+        interpolate_values(&mut values, key, lookup, false).map_err(|(key_path, message)| {
+            BuildError::SettingsInvalid {
+                message: format!("`{}`: {}", key_path, message),
+            }
         })?;
+        Ok(values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SETTINGS: &str = r#"{
+  "version": 3,
+  "workspace_dir": "../ws",
+  "test": { "label": "", "regex": "" },
+  "arguments": {
+    "configure": ["-Wno-dev", 3],
+    "build": []
+  },
+  "variants": {
+    "asan": {
+      "build_type": "Debug",
+      "configure_args": { "label": "a \"quoted\" {value}" }
+    },
+    "tsan": { "build_type": "Debug" }
+  }
+}"#;
 
-        Ok(file_path)
+    #[test]
+    fn locates_top_level_keys() {
+        assert_eq!(find_key_location(SETTINGS, "workspace_dir"), (3, 3));
     }
 
-    // Function to parse a project settings JSON file
-    pub fn parse_json(
-        file_path: &PathBuf,
-    ) -> Result<(StringHashMap, StringHashMap, PathBuf), Box<dyn std::error::Error>> {
-        let json_file = fs::read_to_string(file_path)?;
-        let parsed: Value = serde_json::from_str(&json_file)?;
+    #[test]
+    fn locates_nested_keys_by_path() {
+        assert_eq!(find_key_location(SETTINGS, "test.label"), (4, 13));
+        assert_eq!(
+            find_key_location(SETTINGS, "variants.asan.configure_args.label"),
+            (12, 27)
+        );
+        assert_eq!(
+            find_key_location(SETTINGS, "variants.tsan.build_type"),
+            (14, 15)
+        );
+    }
+
+    #[test]
+    fn locates_array_elements() {
+        assert_eq!(
+            find_key_location(SETTINGS, "arguments.configure[1]"),
+            (6, 31)
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_enclosing_key() {
+        assert_eq!(
+            find_key_location(SETTINGS, "variants.tsan.configure_args"),
+            (14, 5)
+        );
+        assert_eq!(find_key_location(SETTINGS, "unknown"), (1, 1));
+    }
+
+    fn object(contents: &str) -> Map<String, Value> {
+        match serde_json::from_str(contents).unwrap() {
+            Value::Object(values) => values,
+            _ => panic!("not an object"),
+        }
+    }
+
+    #[test]
+    fn rejects_version_zero() {
+        assert!(settings_version(&object(r#"{ "version": 0 }"#)).is_err());
+        assert!(settings_version(&object(r#"{ "version": -1 }"#)).is_err());
+        assert_eq!(settings_version(&object(r#"{ "version": 2 }"#)), Ok(2));
+        assert_eq!(settings_version(&object("{}")), Ok(1));
+    }
+
+    #[test]
+    fn migration_keeps_the_order_of_the_keys() {
+        let settings = object(
+            r#"{
+  "workspace_dir": "../ws",
+  "version": 2,
+  "project_options": {},
+  "extra_args": {},
+  "generator": "Ninja"
+}"#,
+        );
+        let (migrated, _) = migrate_settings(settings, 2);
+        let keys: Vec<&str> = migrated.keys().map(String::as_str).collect();
+        assert_eq!(
+            keys,
+            [
+                "version",
+                "workspace_dir",
+                "project_options",
+                "extra_args",
+                "generator"
+            ]
+        );
+        assert_eq!(migrated[VERSION], json!(SETTINGS_VERSION));
+    }
+
+    #[test]
+    fn migration_adds_no_defaults() {
+        // a default written into the project file would override the user settings
+        let (migrated, changes) = migrate_settings(object(r#"{ "workspace_dir": "../ws" }"#), 1);
+        let keys: Vec<&str> = migrated.keys().map(String::as_str).collect();
+        assert_eq!(keys, ["version", "workspace_dir"]);
+        assert!(changes.is_empty());
+    }
+
+    #[test]
+    fn locates_the_last_of_repeated_keys() {
+        let contents = "{\n  \"generator\": \"Ninja\",\n  \"generator\": \"Xcode\"\n}";
+        assert_eq!(find_key_location(contents, "generator"), (3, 3));
+    }
 
-        let workspace_dir = PathBuf::from(parsed[WORKSPACE_DIR].as_str().unwrap());
+    type Sources = IndexMap<String, (SettingsLayer, Value)>;
 
-        let project_options = parsed[PROJECT_OPTIONS].as_object().unwrap();
-        let mut configure_args: StringHashMap = HashMap::new();
-        for (key, value) in project_options {
-            configure_args.insert(key.to_string(), value.as_str().unwrap().to_string());
+    // Merges the layers in order and returns the merged settings and their sources.
+    fn merge(layers: &[(SettingsLayer, &str)]) -> (Map<String, Value>, Sources) {
+        let mut merged = Map::new();
+        let mut sources = IndexMap::new();
+        for (kind, contents) in layers {
+            merge_layer(&mut merged, &object(contents), "", *kind, &mut sources);
         }
+        (merged, sources)
+    }
+
+    fn layer_of(sources: &Sources, key_path: &str) -> Option<SettingsLayer> {
+        sources.get(key_path).map(|(layer, _)| *layer)
+    }
+
+    #[test]
+    fn merges_objects_key_by_key() {
+        let (merged, sources) = merge(&[
+            (
+                SettingsLayer::User,
+                r#"{ "generator": "Ninja", "test": { "label": "fast", "regex": "unit" } }"#,
+            ),
+            (SettingsLayer::Project, r#"{ "test": { "label": "slow" } }"#),
+        ]);
+        assert_eq!(merged["generator"], json!("Ninja"));
+        assert_eq!(merged["test"], json!({ "label": "slow", "regex": "unit" }));
+        assert_eq!(layer_of(&sources, "generator"), Some(SettingsLayer::User));
+        assert_eq!(
+            layer_of(&sources, "test.label"),
+            Some(SettingsLayer::Project)
+        );
+        assert_eq!(layer_of(&sources, "test.regex"), Some(SettingsLayer::User));
+    }
+
+    #[test]
+    fn replaces_values_that_are_not_both_objects() {
+        let (merged, sources) = merge(&[
+            (
+                SettingsLayer::Project,
+                r#"{ "default_targets": ["app", "lib"] }"#,
+            ),
+            (
+                SettingsLayer::CommandLine,
+                r#"{ "default_targets": ["docs"] }"#,
+            ),
+        ]);
+        // arrays are replaced, not appended to
+        assert_eq!(merged["default_targets"], json!(["docs"]));
+        assert_eq!(
+            layer_of(&sources, "default_targets"),
+            Some(SettingsLayer::CommandLine)
+        );
+
+        let (merged, _) = merge(&[
+            (SettingsLayer::Project, r#"{ "test": "none" }"#),
+            (SettingsLayer::Local, r#"{ "test": { "label": "fast" } }"#),
+        ]);
+        assert_eq!(merged["test"], json!({ "label": "fast" }));
+    }
+
+    #[test]
+    fn drops_the_sources_of_replaced_objects() {
+        let (merged, sources) = merge(&[
+            (
+                SettingsLayer::Project,
+                r#"{ "install": { "prefix": "/opt", "strip": true } }"#,
+            ),
+            (SettingsLayer::Environment, r#"{ "install": null }"#),
+        ]);
+        assert_eq!(merged["install"], Value::Null);
+        assert_eq!(
+            layer_of(&sources, "install"),
+            Some(SettingsLayer::Environment)
+        );
+        assert_eq!(layer_of(&sources, "install.prefix"), None);
+        assert_eq!(layer_of(&sources, "install.strip"), None);
+    }
+
+    #[test]
+    fn records_empty_objects_as_values() {
+        let (_, sources) = merge(&[(
+            SettingsLayer::Project,
+            r#"{ "variants": {}, "arguments": { "build": [] } }"#,
+        )]);
+        let key_paths: Vec<&str> = sources.keys().map(String::as_str).collect();
+        assert_eq!(key_paths, ["variants", "arguments.build"]);
+    }
+
+    #[test]
+    fn replaces_an_option_set_with_another_type() {
+        let mut project_options =
+            object(r#"{ "-DBUILD_TESTS:BOOL": "ON", "-DFOO": "1", "-DBUILD_DOCS": "OFF" }"#);
+        insert_option(&mut project_options, "BUILD_TESTS", "OFF");
+        insert_option(&mut project_options, "FOO:STRING", "2");
+        insert_option(&mut project_options, "BUILD_DOCS", "ON");
+        assert_eq!(
+            Value::Object(project_options),
+            json!({ "-DBUILD_DOCS": "ON", "-DBUILD_TESTS": "OFF", "-DFOO:STRING": "2" })
+        );
+    }
 
-        let user_extra_args = parsed[EXTRA_ARGS].as_object().unwrap();
-        let mut extra_args: StringHashMap = HashMap::new();
-        for (key, value) in user_extra_args {
-            extra_args.insert(key.to_string(), value.as_str().unwrap().to_string());
+    // The variables known when the settings are loaded, with an environment variable
+    // whose value contains a `${`.
+    fn load_time(name: &str) -> Option<String> {
+        match name {
+            "project_dir" => Some(String::from("/work/project")),
+            "env:FLAGS" => Some(String::from("-DX=${notavar}")),
+            _ => None,
         }
+    }
+
+    // Expands like `load_settings` and then `expand_variant_variables` do.
+    fn expand_both_passes(value: &str) -> Result<String, BuildError> {
+        let loaded = interpolate(value, load_time, true).unwrap();
+        let values = IndexMap::from([(String::from("-DVALUE"), loaded)]);
+        let variant = Variant::resolve("release", &[]).unwrap();
+        let expanded = JsonUtil::expand_variant_variables(
+            &values,
+            PROJECT_OPTIONS,
+            &variant,
+            Path::new("/work/build/Release"),
+        )?;
+        Ok(expanded["-DVALUE"].clone())
+    }
+
+    #[test]
+    fn keeps_escapes_for_the_second_pass() {
+        assert_eq!(
+            interpolate("$${project_dir}/${project_dir}", load_time, true),
+            Ok(String::from("$${project_dir}//work/project"))
+        );
+        assert_eq!(
+            interpolate("$${project_dir}", load_time, false),
+            Ok(String::from("${project_dir}"))
+        );
+        assert_eq!(
+            expand_both_passes("$${project_dir}").unwrap(),
+            "${project_dir}"
+        );
+        // a lone `$` is not a variable
+        assert_eq!(
+            interpolate("cost$5", load_time, false),
+            Ok(String::from("cost$5"))
+        );
+    }
+
+    #[test]
+    fn escapes_variables_in_substituted_values() {
+        assert_eq!(
+            interpolate("${env:FLAGS}", load_time, true),
+            Ok(String::from("-DX=$${notavar}"))
+        );
+        assert_eq!(
+            expand_both_passes("${env:FLAGS}").unwrap(),
+            "-DX=${notavar}"
+        );
+    }
+
+    #[test]
+    fn defers_the_variant_variables() {
+        assert_eq!(
+            interpolate("${build_dir}/${variant}", load_time, true),
+            Ok(String::from("${build_dir}/${variant}"))
+        );
+        assert_eq!(
+            expand_both_passes("${project_dir}/out/${variant}:${build_dir}").unwrap(),
+            "/work/project/out/Release:/work/build/Release"
+        );
+    }
+
+    #[test]
+    fn rejects_unterminated_variables() {
+        let message = interpolate("${project_dir", load_time, true).unwrap_err();
+        assert!(message.starts_with("unterminated variable"), "{}", message);
+    }
 
-        Ok((configure_args, extra_args, workspace_dir))
+    #[test]
+    fn rejects_undefined_variables() {
+        assert_eq!(
+            interpolate("${env:BUILD_IT_NOT_SET}", load_time, true),
+            Err(String::from(
+                "environment variable `BUILD_IT_NOT_SET` is not set"
+            ))
+        );
+        let message = interpolate("${projectdir}", load_time, true).unwrap_err();
+        assert!(
+            message.starts_with("undefined variable `${projectdir}`"),
+            "{}",
+            message
+        );
+        // only the variant variables wait for the second pass
+        assert_eq!(
+            interpolate("${variant}", load_time, false),
+            Err(String::from("`${variant}` can't be used in this setting"))
+        );
+        let error = JsonUtil::expand_variant_variables(
+            &IndexMap::from([(String::from("-DDIR"), String::from("${workspace}"))]),
+            PROJECT_OPTIONS,
+            &Variant::resolve("debug", &[]).unwrap(),
+            Path::new("/work/build/Debug"),
+        )
+        .unwrap_err();
+        assert!(
+            error.to_string().contains("`project_options.-DDIR`"),
+            "{}",
+            error
+        );
     }
 }
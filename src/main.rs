@@ -7,59 +7,136 @@
 mod app_context;
 use app_context::*;
 
+mod build_command;
+mod build_error;
+use build_error::*;
+mod build_log;
+
 mod cmake_build;
 use cmake_build::*;
+mod cmake_cache;
+mod cmake_parser;
 
 mod command_args;
+use command_args::Command;
+mod file_api;
 mod json_helper;
+use json_helper::*;
 mod utility_helper;
 
 use colored::*;
-use std::error::Error;
+use std::process::ExitCode;
 
-fn main() -> Result<(), Box<dyn Error>> {
+fn main() -> ExitCode {
+    match run() {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("{}", format!("Error: {}", e).red().bold());
+            e.exit_code()
+        }
+    }
+}
+
+// Describes the settings layer a value came from, e.g. "(local settings)".
+fn describe_source(source: Option<SettingsLayer>) -> String {
+    match source {
+        Some(layer) => format!("({})", layer),
+        None => String::from("(default)"),
+    }
+}
+
+// Returns the exit code of the process, which is the program's own with `run`.
+fn run() -> Result<ExitCode, BuildError> {
     let context = AppContext::new()?;
 
+    if context.show_settings {
+        println!("{}", "Resolved settings".blue().bold());
+        for (key_path, (layer, value)) in &context.settings_sources {
+            println!(
+                "\t{} = {}  {}",
+                key_path.cyan(),
+                value,
+                format!("({})", layer).dimmed()
+            );
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+
     if !context.workspace_dir.exists() {
-        println!(
-            "{}",
-            "Warning: please set `workspace_dir` in project_settings json."
-                .yellow()
-                .bold()
-        );
-    } else {
-        let project_name_colored = format!("{:?}", &context.project_name).cyan();
-        let project_location_colored = format!("{:?}", context.project_location).cyan();
-        let workspace_location_colored = format!("{:?}", context.workspace_dir).cyan();
-        let stages_colored = format!("{:?}", &context.stages).cyan();
-        let variant_colored = format!("{:?}", &context.variant).cyan();
-        let build_log_location_colored = format!("{:?}", &context.build_log_location).cyan();
-
-        let mut summary_msg = format!(
-            "Building with settings\n\
-             \tProject name:       {}\n\
-             \tProject directory:  {}\n\
-             \tWorkspace directory:  {}\n\
-             \tStages arguments:     {}\n\
-             \tVariant:     {}",
-            project_name_colored,
-            project_location_colored,
-            workspace_location_colored,
-            stages_colored,
-            variant_colored
-        );
-
-        if context.redirect_outstream.is_some() {
-            summary_msg.push_str(&format!("\n\tBuild Log: {}", build_log_location_colored));
+        return Err(BuildError::WorkspaceMissing {
+            workspace_dir: context.workspace_dir.clone(),
+        });
+    }
+
+    match &context.command {
+        Some(Command::Targets { json }) => {
+            list_targets(&context, *json)?;
+            return Ok(ExitCode::SUCCESS);
         }
+        Some(Command::Run { target, args }) => return run_target(&context, target, args),
+        None => {}
+    }
 
-        println!("{}", summary_msg.blue());
+    let project_name_colored = format!("{:?}", &context.project_name).cyan();
+    let project_location_colored = format!("{:?}", context.project_location).cyan();
+    let workspace_location_colored = format!(
+        "{:?} {}",
+        context.workspace_dir,
+        describe_source(context.setting_source("workspace_dir"))
+    )
+    .cyan();
+    let stages_colored = format!("{:?}", &context.stages).cyan();
+    let variant_names: Vec<&str> = context.variants.iter().map(|v| v.as_str()).collect();
+    let variant_colored = format!("{:?}", variant_names).cyan();
+    let generator_colored = format!(
+        "{:?} {}",
+        &context.generator,
+        describe_source(context.setting_source("generator"))
+    )
+    .cyan();
 
-        // build & install
-        time_it!({
-            build_project(&context)?;
-        });
+    let mut summary_msg = format!(
+        "Building with settings\n\
+         \tProject name:       {}\n\
+         \tProject directory:  {}\n\
+         \tWorkspace directory:  {}\n\
+         \tStages arguments:     {}\n\
+         \tVariants:     {}\n\
+         \tGenerator:     {}",
+        project_name_colored,
+        project_location_colored,
+        workspace_location_colored,
+        stages_colored,
+        variant_colored,
+        generator_colored
+    );
+
+    if !context.targets.is_empty() {
+        let targets_colored = format!(
+            "{:?} {}",
+            context.targets,
+            describe_source(context.setting_source("default_targets"))
+        )
+        .cyan();
+        summary_msg.push_str(&format!("\n\tTargets:     {}", targets_colored));
+    }
+
+    if context.parallel_variants {
+        summary_msg.push_str("\n\tBuilding variants in parallel");
     }
 
-    Ok(())
+    if context.redirect_outstream.is_some() {
+        for variant in &context.variants {
+            let build_log_location_colored =
+                format!("{:?}", context.build_log_location(variant)).cyan();
+            summary_msg.push_str(&format!("\n\tBuild Log: {}", build_log_location_colored));
+        }
+    }
+
+    println!("{}", summary_msg.blue());
+
+    // build & install
+    time_it!({ build_project(&context) })?;
+
+    Ok(ExitCode::SUCCESS)
 }
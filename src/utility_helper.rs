@@ -1,29 +1,18 @@
-use regex::Regex;
-use std::collections::HashMap;
-use std::env;
-use std::error::Error;
+use colored::*;
+use indexmap::IndexMap;
+use std::collections::HashSet;
 use std::fs;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
-use std::path::PathBuf;
-use walkdir::WalkDir;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::cmake_cache::*;
+use super::cmake_parser::{self, *};
 
 pub struct UtilityHelper;
 
 impl UtilityHelper {
-    // This function changes the current working directory to the given directory, calls the provided
-    // closure, and then changes the current working directory back to the original directory.
-    pub fn current_working_directory<F, T>(dir: &PathBuf, f: F) -> Result<T, Box<dyn Error>>
-    where
-        F: FnOnce() -> Result<T, Box<dyn Error>>,
-    {
-        let curdir = env::current_dir()?;
-        env::set_current_dir(dir)?;
-        let result = f()?;
-        env::set_current_dir(curdir)?;
-        Ok(result)
-    }
-
     // Deletes a directory and all its contents. Returns an error if the directory cannot be deleted.
     pub fn delete_directory(dir_path: &PathBuf) -> std::io::Result<()> {
         match fs::remove_dir_all(dir_path) {
@@ -56,85 +45,212 @@ impl UtilityHelper {
         })
     }
 
-    // Parses the `CMakeLists.txt` file and all `*.cmake` files in the `./cmake` directory
-    // to extract CMake project options defined using the `option()` command.
-    // Returns a HashMap of the options and their values.
-    pub fn fetch_cmake_project_options() -> HashMap<String, String> {
-        let contents = fs::read_to_string("CMakeLists.txt").expect("Error reading file!");
-        let re = Regex::new(r#"option\((\w+)\s+".*?"\s+(ON|OFF)\)"#).unwrap();
+    // Reads and parses a listfile, a syntax error is reported as invalid data.
+    fn parse_listfile(path: &Path) -> std::io::Result<Vec<CommandInvocation>> {
+        let contents = fs::read_to_string(path)?;
+        cmake_parser::parse(&contents).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            )
+        })
+    }
 
-        let mut options = HashMap::new();
+    // Walks the listfiles reachable from the top level `CMakeLists.txt` of `project_dir`
+    // through `include()` and `add_subdirectory()` to extract the options declared with
+    // `option()` and `cmake_dependent_option()`. Conditions are not evaluated, every
+    // branch is followed. Returns the options, keyed by name, in the order CMake declares them.
+    pub fn fetch_cmake_project_options(
+        project_dir: &Path,
+    ) -> std::io::Result<IndexMap<String, ProjectOption>> {
+        let mut variables = Variables::default();
+        for name in [
+            "CMAKE_SOURCE_DIR",
+            "PROJECT_SOURCE_DIR",
+            "CMAKE_CURRENT_SOURCE_DIR",
+        ] {
+            variables.define(name, &project_dir.display().to_string());
+        }
 
-        // also walk the the top level cmake file as well for any additional options
-        for entry in WalkDir::new("./cmake").into_iter().filter_map(|e| e.ok()) {
-            if entry.path().extension().and_then(|e| e.to_str()) == Some("cmake") {
-                let contents = fs::read_to_string(entry.path()).unwrap();
+        let mut walker = ListfileWalker::default();
+        walker.visit(
+            &project_dir.join("CMakeLists.txt"),
+            project_dir,
+            &mut variables,
+        )?;
+        Ok(walker.options)
+    }
 
-                for line in contents.lines() {
-                    if let Some(captures) = re.captures(line) {
-                        let option_name = "-D".to_string() + &captures[1].to_string();
-                        options.insert(option_name, captures[2].to_string());
-                    }
-                }
-            }
-        }
+    // Reads the name given to `project()` in the top level CMakeLists.txt of `project_dir`.
+    pub fn fetch_project_name(project_dir: &Path) -> Option<String> {
+        let commands = Self::parse_listfile(&project_dir.join("CMakeLists.txt")).ok()?;
+        find_project_name(&commands)
+    }
 
-        for cap in re.captures_iter(&contents) {
-            let option_name = "-D".to_string() + &cap[1].to_string();
-            options.insert(option_name, cap[2].to_string());
+    // Reads the generator recorded in the `CMakeCache.txt` of an already configured
+    // build directory. Returns None if the directory has not been configured yet.
+    pub fn fetch_cache_generator(build_dir: &Path) -> Option<String> {
+        read_cache(build_dir)?
+            .into_iter()
+            .find(|entry| entry.name == "CMAKE_GENERATOR")
+            .map(|entry| entry.value.trim().to_string())
+    }
+
+    // Asks `question` on the console, on stderr with `to_stderr`, and returns the trimmed
+    // answer, or None without asking when stdin is not a terminal.
+    pub fn prompt(question: &str, to_stderr: bool) -> std::io::Result<Option<String>> {
+        if !io::stdin().is_terminal() {
+            return Ok(None);
+        }
+        if to_stderr {
+            eprint!("{}", question.blue().bold());
+            io::stderr().flush()?;
+        } else {
+            print!("{}", question.blue().bold());
+            io::stdout().flush()?;
         }
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer)?;
+        Ok(Some(answer.trim().to_string()))
+    }
 
-        return options;
+    // Prints a progress message on stderr with `to_stderr`, on stdout otherwise.
+    pub fn print_status(msg: ColoredString, to_stderr: bool) {
+        if to_stderr {
+            eprintln!("{}", msg);
+        } else {
+            println!("{}", msg);
+        }
     }
 
-    pub fn fetch_project_name() -> Option<String> {
-        // Open the CMakeLists.txt file
-        let file = File::open("CMakeLists.txt").ok()?;
-        let reader = BufReader::new(file);
-
-        // Loop through each line in the file
-        let mut in_project_block = false;
-        for line in reader.lines() {
-            let line = line.ok()?;
-            let trimmed = line.trim();
-
-            // Check if we're currently inside the project block
-            if in_project_block {
-                // If the line ends with a parenthesis, extract the project name
-                if let Some(captures) = regex::Regex::new(r"^\s*([^\s()]+)")
-                    .unwrap()
-                    .captures(trimmed)
-                {
-                    return Some(captures[1].to_owned());
-                }
-            } else if trimmed.starts_with("project(") {
-                // If the line starts a new project block, mark that we're inside it
-                in_project_block = true;
-
-                // Extract the project name from the line
-                if let Some(captures) = regex::Regex::new(r#"^project\(\s*"?([^\s"]+)"?\s*"#)
-                    .unwrap()
-                    .captures(trimmed)
-                {
-                    let mut project_name = captures[1].to_owned();
-                    project_name = project_name.trim_end_matches(')').to_string();
-                    return Some(project_name);
-                }
-            }
+    // Formats a size in bytes with a binary unit suffix, e.g. "1.5 MiB".
+    pub fn format_size(bytes: u64) -> String {
+        const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+        let mut size = bytes as f64;
+        let mut unit = 0;
+        while size >= 1024.0 && unit < UNITS.len() - 1 {
+            size /= 1024.0;
+            unit += 1;
+        }
+        if unit == 0 {
+            format!("{} {}", bytes, UNITS[unit])
+        } else {
+            format!("{:.1} {}", size, UNITS[unit])
         }
+    }
 
-        None
+    // Formats a duration as "HH:MM:SS".
+    pub fn format_duration(elapsed: Duration) -> String {
+        let elapsed_seconds = elapsed.as_secs();
+        let hours = elapsed_seconds / 3600;
+        let minutes = (elapsed_seconds % 3600) / 60;
+        let seconds = elapsed_seconds % 60;
+        format!("{:02}:{:02}:{:02}", hours, minutes, seconds)
     }
 
-    // converts a HashMap of string key-value
-    // pairs into a string of space-separated
-    // key-value pairs in the format "key=value".
-    pub fn stringify(entry: &HashMap<String, String>) -> String {
+    // converts an ordered map of string key-value
+    // pairs into a list of command line
+    // arguments in the format "key=value",
+    // keeping the order of the map.
+    pub fn to_arguments(entry: &IndexMap<String, String>) -> Vec<String> {
         entry
             .iter()
             .map(|(key, value)| format!("{}={}", key, value))
-            .collect::<Vec<String>>()
-            .join(" ")
+            .collect()
+    }
+}
+
+// Follows `include()` and `add_subdirectory()` from a listfile, collecting the options of
+// every file it reaches. Each file is read once.
+#[derive(Default)]
+struct ListfileWalker {
+    options: IndexMap<String, ProjectOption>,
+    visited: HashSet<PathBuf>,
+}
+
+impl ListfileWalker {
+    // Evaluates the listfile `path` in the scope of `variables`, `source_dir` is the
+    // directory of the CMakeLists.txt being processed (CMAKE_CURRENT_SOURCE_DIR).
+    fn visit(
+        &mut self,
+        path: &Path,
+        source_dir: &Path,
+        variables: &mut Variables,
+    ) -> std::io::Result<()> {
+        let path = path.canonicalize().map_err(|e| {
+            std::io::Error::new(e.kind(), format!("listfile {}: {}", path.display(), e))
+        })?;
+        if !self.visited.insert(path.clone()) {
+            return Ok(());
+        }
+        let commands = match UtilityHelper::parse_listfile(&path) {
+            Ok(commands) => commands,
+            // cmake itself will report the error, the options of the other files are still useful
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                let msg = format!("Warning: skipping listfile {}", e);
+                eprintln!("{}", msg.yellow());
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        let list_dir = path
+            .parent()
+            .map(|dir| dir.display().to_string())
+            .unwrap_or_default();
+        variables.define("CMAKE_CURRENT_LIST_DIR", &list_dir);
+
+        for command in &commands {
+            if let Some(option) = evaluate(command, &path, variables) {
+                // like CMake, the first declaration of an option sets its default
+                self.options.entry(option.name.clone()).or_insert(option);
+                continue;
+            }
+            let Some(argument) = command.arguments.first() else {
+                continue;
+            };
+            let argument = variables.expand(&argument.value);
+            match command.name.as_str() {
+                "include" => {
+                    if let Some(file) = Self::resolve_include(&argument, source_dir, variables) {
+                        self.visit(&file, source_dir, variables)?;
+                        variables.define("CMAKE_CURRENT_LIST_DIR", &list_dir);
+                    }
+                }
+                "add_subdirectory" => {
+                    let dir = source_dir.join(argument);
+                    let listfile = dir.join("CMakeLists.txt");
+                    if listfile.is_file() {
+                        // a subdirectory gets a copy of the variables of its parent
+                        let mut scope = variables.clone();
+                        scope.define("CMAKE_CURRENT_SOURCE_DIR", &dir.display().to_string());
+                        self.visit(&listfile, &dir, &mut scope)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Finds the file loaded by `include(<file|module>)`: a file path is relative to the
+    // current source directory, a module is searched in CMAKE_MODULE_PATH. Modules that
+    // ship with CMake and files that don't exist are skipped.
+    fn resolve_include(
+        argument: &str,
+        source_dir: &Path,
+        variables: &Variables,
+    ) -> Option<PathBuf> {
+        if argument.ends_with(".cmake") || argument.contains('/') || argument.contains('\\') {
+            let file = source_dir.join(argument);
+            return file.is_file().then_some(file);
+        }
+        variables
+            .get("CMAKE_MODULE_PATH")
+            .unwrap_or_default()
+            .split(';')
+            .filter(|dir| !dir.is_empty())
+            .map(|dir| source_dir.join(dir).join(format!("{}.cmake", argument)))
+            .find(|file| file.is_file())
     }
 }
 
@@ -142,16 +258,15 @@ impl UtilityHelper {
 macro_rules! time_it {
     ($code:block) => {{
         let start_time = std::time::Instant::now();
-        $code;
+        let result = $code;
         let elapsed = std::time::Instant::now() - start_time;
-        let elapsed_seconds = elapsed.as_secs();
-        let hours = elapsed_seconds / 3600;
-        let minutes = (elapsed_seconds % 3600) / 60;
-        let seconds = elapsed_seconds % 60;
-        let eplased_time_colored =
-            format!("Elapsed time: {:02}:{:02}:{:02}", hours, minutes, seconds)
-                .blue()
-                .bold();
+        let eplased_time_colored = format!(
+            "Elapsed time: {}",
+            $crate::utility_helper::UtilityHelper::format_duration(elapsed)
+        )
+        .blue()
+        .bold();
         println!("{}", eplased_time_colored);
+        result
     }};
 }